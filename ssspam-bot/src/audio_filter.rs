@@ -0,0 +1,383 @@
+//! Validation of the user-supplied `af=` argument.
+//!
+//! The text after `af=` used to be handed to ffmpeg verbatim, which let anyone in the text
+//! channel use filters such as `amovie` to read files on the host. Only a small set of filters
+//! with numeric, bounded parameters is accepted here, and [`crate::dsp`] implements each of them.
+
+use std::str::FromStr;
+
+/// Maximum number of filters in a single chain.
+const MAX_FILTERS: usize = 8;
+
+/// Maximum number of items in a `|`-separated list parameter (e.g. `aecho` delays).
+const MAX_LIST_ITEMS: usize = 8;
+
+struct ParamSpec {
+    /// Accepted names; the first one is canonical.
+    names: &'static [&'static str],
+    min: f64,
+    max: f64,
+    list: bool,
+}
+
+impl ParamSpec {
+    const fn scalar(names: &'static [&'static str], min: f64, max: f64) -> Self {
+        Self {
+            names,
+            min,
+            max,
+            list: false,
+        }
+    }
+
+    const fn list(names: &'static [&'static str], min: f64, max: f64) -> Self {
+        Self {
+            names,
+            min,
+            max,
+            list: true,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.names[0]
+    }
+}
+
+struct FilterSpec {
+    name: &'static str,
+    /// Parameters in ffmpeg's positional order.
+    params: &'static [ParamSpec],
+}
+
+static ALLOWED_FILTERS: &[FilterSpec] = &[
+    FilterSpec {
+        name: "aecho",
+        params: &[
+            ParamSpec::scalar(&["in_gain"], 0.0, 1.0),
+            ParamSpec::scalar(&["out_gain"], 0.0, 1.0),
+            ParamSpec::list(&["delays"], 1.0, 5000.0),
+            ParamSpec::list(&["decays"], 0.0, 1.0),
+        ],
+    },
+    FilterSpec {
+        name: "atempo",
+        params: &[ParamSpec::scalar(&["tempo"], 0.5, 2.0)],
+    },
+    FilterSpec {
+        name: "highpass",
+        params: &[ParamSpec::scalar(&["frequency", "f"], 20.0, 20000.0)],
+    },
+    FilterSpec {
+        name: "lowpass",
+        params: &[ParamSpec::scalar(&["frequency", "f"], 20.0, 20000.0)],
+    },
+    FilterSpec {
+        name: "tremolo",
+        params: &[
+            ParamSpec::scalar(&["f"], 0.1, 20000.0),
+            ParamSpec::scalar(&["d"], 0.0, 1.0),
+        ],
+    },
+    FilterSpec {
+        name: "volume",
+        params: &[ParamSpec::scalar(&["volume"], 0.0, 4.0)],
+    },
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioFilterError {
+    Empty,
+    TooManyFilters,
+    UnknownFilter(String),
+    UnknownParameter { filter: String, param: String },
+    TooManyParameters { filter: String },
+    EmptyParameter { filter: String },
+    InvalidValue { param: String, value: String },
+    OutOfRange { param: String, min: f64, max: f64 },
+}
+
+impl std::fmt::Display for AudioFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty audio filter"),
+            Self::TooManyFilters => write!(f, "at most {MAX_FILTERS} filters can be chained"),
+            Self::UnknownFilter(name) => write!(
+                f,
+                "filter `{name}` is not allowed (allowed: {})",
+                ALLOWED_FILTERS
+                    .iter()
+                    .map(|spec| spec.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::UnknownParameter { filter, param } => {
+                write!(f, "`{filter}` has no parameter `{param}`")
+            }
+            Self::TooManyParameters { filter } => write!(f, "too many parameters for `{filter}`"),
+            Self::EmptyParameter { filter } => write!(f, "empty parameter for `{filter}`"),
+            Self::InvalidValue { param, value } => {
+                write!(f, "`{value}` is not a valid value for `{param}`")
+            }
+            Self::OutOfRange { param, min, max } => {
+                write!(f, "`{param}` must be between {min} and {max}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AudioFilterError {}
+
+/// A single allowlisted filter with its parameters resolved to canonical names.
+#[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Hash)]
+pub struct Filter {
+    pub name: &'static str,
+    /// Canonical parameter name and its validated textual value, in ffmpeg's positional order.
+    pub params: Vec<(&'static str, String)>,
+}
+
+impl Filter {
+    fn parse(s: &str) -> Result<Self, AudioFilterError> {
+        let (name, args) = match s.split_once('=') {
+            Some((name, args)) => (name, Some(args)),
+            None => (s, None),
+        };
+        let spec = ALLOWED_FILTERS
+            .iter()
+            .find(|spec| spec.name == name)
+            .ok_or_else(|| AudioFilterError::UnknownFilter(name.to_owned()))?;
+
+        let mut values: Vec<Option<String>> = vec![None; spec.params.len()];
+        let mut named_seen = false;
+        for (i, arg) in args
+            .into_iter()
+            .flat_map(|args| args.split(':'))
+            .enumerate()
+        {
+            // Skipping an empty argument would shift the positions of the ones after it.
+            if arg.is_empty() {
+                return Err(AudioFilterError::EmptyParameter {
+                    filter: spec.name.to_owned(),
+                });
+            }
+            let (idx, value) = match arg.split_once('=') {
+                Some((key, value)) => {
+                    named_seen = true;
+                    let idx = spec
+                        .params
+                        .iter()
+                        .position(|p| p.names.contains(&key))
+                        .ok_or_else(|| AudioFilterError::UnknownParameter {
+                            filter: spec.name.to_owned(),
+                            param: key.to_owned(),
+                        })?;
+                    (idx, value)
+                }
+                // Positional arguments are only allowed before named ones, as in ffmpeg.
+                None if !named_seen && i < spec.params.len() => (i, arg),
+                None => {
+                    return Err(AudioFilterError::TooManyParameters {
+                        filter: spec.name.to_owned(),
+                    });
+                }
+            };
+            validate_value(&spec.params[idx], value)?;
+            values[idx] = Some(value.to_owned());
+        }
+
+        Ok(Self {
+            name: spec.name,
+            params: spec
+                .params
+                .iter()
+                .zip(values)
+                .filter_map(|(p, v)| v.map(|v| (p.name(), v)))
+                .collect(),
+        })
+    }
+
+    fn spec(&self) -> &'static FilterSpec {
+        ALLOWED_FILTERS
+            .iter()
+            .find(|spec| spec.name == self.name)
+            .expect("Filters are only parsed from allowed ones")
+    }
+
+    /// Returns the value of a scalar parameter.
+    pub fn get(&self, param: &str) -> Option<f64> {
+        self.get_list(param)
+            .and_then(|values| values.first().copied())
+    }
+
+    /// Returns the values of a (possibly `|`-separated) parameter.
+    pub fn get_list(&self, param: &str) -> Option<Vec<f64>> {
+        self.params
+            .iter()
+            .find(|(name, _)| *name == param)
+            .map(|(_, value)| value.split('|').filter_map(|v| v.parse().ok()).collect())
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        // The only parameter of a filter needs no name, as in `volume=0.5`.
+        if let [(_, value)] = self.params.as_slice()
+            && self.spec().params.len() == 1
+        {
+            return write!(f, "={value}");
+        }
+        for (i, (name, value)) in self.params.iter().enumerate() {
+            let sep = if i == 0 { '=' } else { ':' };
+            write!(f, "{sep}{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_value(spec: &ParamSpec, value: &str) -> Result<(), AudioFilterError> {
+    let items: Vec<_> = if spec.list {
+        value.split('|').collect()
+    } else {
+        vec![value]
+    };
+    if items.len() > MAX_LIST_ITEMS {
+        return Err(AudioFilterError::InvalidValue {
+            param: spec.name().to_owned(),
+            value: value.to_owned(),
+        });
+    }
+    for item in items {
+        // Only plain decimals: no expressions, units, exponents, `inf` or `nan`.
+        let n = item
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.' || c == '-')
+            .then(|| item.parse::<f64>().ok())
+            .flatten()
+            .ok_or_else(|| AudioFilterError::InvalidValue {
+                param: spec.name().to_owned(),
+                value: item.to_owned(),
+            })?;
+        if !(spec.min..=spec.max).contains(&n) {
+            return Err(AudioFilterError::OutOfRange {
+                param: spec.name().to_owned(),
+                min: spec.min,
+                max: spec.max,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A validated `af=` filter chain such as `aecho=0.8:0.88:60:0.4,volume=0.5`.
+///
+/// Its [`Display`](std::fmt::Display) output is the canonical form, in ffmpeg's syntax.
+#[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Hash)]
+pub struct AudioFilter(Vec<Filter>);
+
+impl AudioFilter {
+    pub fn filters(&self) -> &[Filter] {
+        &self.0
+    }
+}
+
+impl FromStr for AudioFilter {
+    type Err = AudioFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(AudioFilterError::Empty);
+        }
+        let filters = s
+            .split(',')
+            .map(Filter::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if filters.len() > MAX_FILTERS {
+            return Err(AudioFilterError::TooManyFilters);
+        }
+        Ok(Self(filters))
+    }
+}
+
+impl std::fmt::Display for AudioFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let filters: Vec<_> = self.0.iter().map(|filter| filter.to_string()).collect();
+        write!(f, "{}", filters.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_allowed_filters() {
+        let af = AudioFilter::from_str("aecho=0.8:0.9:1000|1800:0.3|0.25").unwrap();
+        assert_eq!(
+            af.to_string(),
+            "aecho=in_gain=0.8:out_gain=0.9:delays=1000|1800:decays=0.3|0.25"
+        );
+        assert_eq!(
+            af.filters()[0].get_list("delays"),
+            Some(vec![1000.0, 1800.0])
+        );
+
+        let af = AudioFilter::from_str("highpass=f=200,volume=volume=0.5,tremolo=d=0.5").unwrap();
+        assert_eq!(af.to_string(), "highpass=200,volume=0.5,tremolo=d=0.5");
+        assert_eq!(af.filters()[1].get("volume"), Some(0.5));
+
+        // The canonical form parses back to the same chain.
+        assert_eq!(AudioFilter::from_str(&af.to_string()).unwrap(), af);
+    }
+
+    #[test]
+    fn test_reject_hostile_filters() {
+        for af in [
+            "",
+            "amovie=/etc/passwd",
+            "movie=filename=/etc/passwd",
+            "volume=1,amovie=/etc/passwd",
+            "volume=1[out];[out]amovie=/etc/passwd",
+            "volume='sin(t)'",
+            "volume=eval=frame",
+            "volume=1e9",
+            "volume=inf",
+            "volume=nan",
+            "volume=6dB",
+            "volume=1:2",
+            "volume=",
+            "atempo=0.1",
+            "aecho=0.8::60",
+            "aecho=0.8:0.9:60:0.4:",
+            "aecho=0.8:0.9:99999:0.3",
+            "aecho=0.8:0.9:1|2|3|4|5|6|7|8|9:0.3",
+            "asplit,aecho",
+            "volume=1\\,amovie=x",
+            &["volume=1"; 9].join(","),
+        ] {
+            assert!(AudioFilter::from_str(af).is_err(), "accepted {af:?}");
+        }
+    }
+
+    #[test]
+    fn test_rejection_reasons() {
+        assert_eq!(
+            AudioFilter::from_str("amovie=/etc/passwd").unwrap_err(),
+            AudioFilterError::UnknownFilter("amovie".to_owned())
+        );
+        assert_eq!(
+            AudioFilter::from_str("atempo=3").unwrap_err(),
+            AudioFilterError::OutOfRange {
+                param: "tempo".to_owned(),
+                min: 0.5,
+                max: 2.0
+            }
+        );
+        assert_eq!(
+            AudioFilter::from_str("aecho=0.8::60").unwrap_err(),
+            AudioFilterError::EmptyParameter {
+                filter: "aecho".to_owned()
+            }
+        );
+    }
+}
@@ -6,7 +6,7 @@ use chrono::{DateTime, Utc};
 use itertools::Itertools;
 use prettytable::{Table, format};
 use serenity::{
-    all::{Attachment, Context as SerenityContext},
+    all::{Attachment, Context as SerenityContext, CreateAttachment},
     model::{id::GuildId, prelude::UserId},
     prelude::Mentionable,
 };
@@ -15,14 +15,33 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{info, warn};
 
 use crate::{
-    ChannelManager, Configs, GuildBroadcast, OpsMessage, SayCommands, SaySoundCache, SoundStorage,
-    core::{ChannelUserManager, process_from_string},
+    ChannelManager, Configs, MacroStorage, SayCommands, SaySoundCache, SoundStorage,
+    core::{
+        ChannelUserManager, PlaybackCoordinator, parse_say_commands, process_from_say_commands,
+        process_from_string, stop_playback,
+    },
     interpret_rhai,
+    macros::Macro,
+    midi::{DEFAULT_BASE_NOTE, say_commands_from_midi},
+    render_say_commands_in_context,
+    synth::Synth,
     web::update_sounds_bin,
 };
 
 type Context<'a> = poise::Context<'a, (), anyhow::Error>;
 
+/// Number of waiting say commands listed by `~queue`.
+const MAX_LISTED_PENDING: usize = 10;
+
+/// Length at which a waiting say command is cut off in `~queue`.
+const MAX_PENDING_CHARS: usize = 100;
+
+/// Largest file `~render` attaches, which is Discord's upload limit for servers without boosts.
+const MAX_ATTACHMENT_BYTES: usize = 10 * 1024 * 1024;
+
+/// Largest MIDI file `~midi` downloads. Real songs are far smaller.
+const MAX_MIDI_BYTES: u32 = 1024 * 1024;
+
 #[poise::command(prefix_command)]
 pub async fn help(
     ctx: Context<'_>,
@@ -253,36 +272,70 @@ pub async fn unmute(ctx: Context<'_>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Stops what is playing. Say commands waiting in the queue play next; `~queue clear` drops them
 #[poise::command(prefix_command, guild_only)]
 pub async fn stop(ctx: Context<'_>) -> anyhow::Result<()> {
-    let manager = songbird::get(ctx.as_ref())
+    let guild_id = ctx.guild_id().context("Guild was not found")?;
+    if !stop_playback(ctx.serenity_context(), guild_id).await? {
+        ctx.reply("Not in a voice channel").await?;
+    }
+    Ok(())
+}
+
+/// Shows the say commands waiting to be played when the playback policy is `queue`
+#[poise::command(prefix_command, guild_only, subcommands("queue_clear"))]
+pub async fn queue(ctx: Context<'_>) -> anyhow::Result<()> {
+    let guild_id = ctx.guild_id().context("Guild was not found")?;
+    let coordinator = ctx
+        .serenity_context()
+        .data
+        .read()
         .await
-        .context("Songbird Voice client placed in at initialization.")?
+        .get::<PlaybackCoordinator>()
+        .context("Could not get PlaybackCoordinator")?
         .clone();
+    let pending = coordinator.pending(guild_id);
+    if pending.is_empty() {
+        ctx.reply("Nothing is waiting").await?;
+        return Ok(());
+    }
 
-    let handler_lock = match manager.get(ctx.guild_id().context("Guild was not found")?) {
-        Some(handler) => handler,
-        None => {
-            ctx.reply("Not in a voice channel").await?;
-            return Ok(());
-        }
-    };
-    handler_lock.lock().await.stop();
+    let mut lines: Vec<_> = pending
+        .iter()
+        .take(MAX_LISTED_PENDING)
+        .enumerate()
+        .map(|(i, saycmds)| {
+            let saycmds = saycmds.to_string();
+            if saycmds.chars().count() > MAX_PENDING_CHARS {
+                let head: String = saycmds.chars().take(MAX_PENDING_CHARS - 1).collect();
+                format!("{}. {head}…", i + 1)
+            } else {
+                format!("{}. {saycmds}", i + 1)
+            }
+        })
+        .collect();
+    if pending.len() > MAX_LISTED_PENDING {
+        lines.push(format!("and {} more", pending.len() - MAX_LISTED_PENDING));
+    }
+    ctx.reply(format!("```\n{}\n```", lines.join("\n"))).await?;
+    Ok(())
+}
 
-    let guild_broadcast = ctx
+/// Drops the say commands waiting to be played
+#[poise::command(prefix_command, rename = "clear", guild_only)]
+pub async fn queue_clear(ctx: Context<'_>) -> anyhow::Result<()> {
+    let guild_id = ctx.guild_id().context("Guild was not found")?;
+    let coordinator = ctx
         .serenity_context()
         .data
         .read()
         .await
-        .get::<GuildBroadcast>()
-        .context("Could not get GuildBroadcast")?
+        .get::<PlaybackCoordinator>()
+        .context("Could not get PlaybackCoordinator")?
         .clone();
-    let tx = guild_broadcast
-        .lock()
-        .unwrap()
-        .get_sender(ctx.guild_id().context("Guild was not found")?);
-    tx.send(OpsMessage::Stop)?;
-
+    let cleared = coordinator.clear(guild_id);
+    ctx.reply(format!("Cleared {cleared} waiting say commands"))
+        .await?;
     Ok(())
 }
 
@@ -303,6 +356,17 @@ async fn clean_cache_inner(ctx: &SerenityContext) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Drops the cached renders of the sounds called `names`, which were just replaced or deleted.
+async fn invalidate_cache(ctx: &SerenityContext, names: &[String]) -> anyhow::Result<()> {
+    ctx.data
+        .read()
+        .await
+        .get::<SaySoundCache>()
+        .context("Could not get SaySoundCache")?
+        .invalidate(names);
+    Ok(())
+}
+
 #[poise::command(prefix_command)]
 pub async fn r(ctx: Context<'_>, #[rest] rest: Option<String>) -> anyhow::Result<()> {
     let storage = ctx
@@ -339,15 +403,38 @@ pub async fn s(ctx: Context<'_>, query: String) -> anyhow::Result<()> {
             .get::<SoundStorage>()
             .unwrap()
             .clone();
-        let sims = storage.read().unwrap().calc_similarities(query);
+        let macros = ctx
+            .serenity_context()
+            .data
+            .read()
+            .await
+            .get::<MacroStorage>()
+            .unwrap()
+            .clone();
+        let mut sims: Vec<_> = storage
+            .read()
+            .unwrap()
+            .calc_similarities(&query)
+            .into_iter()
+            .map(|(s, f)| (s, f.name))
+            .chain(
+                macros
+                    .read()
+                    .unwrap()
+                    .calc_similarities(&query)
+                    .into_iter()
+                    .map(|(s, m)| (s, m.name)),
+            )
+            .collect();
+        sims.sort_by(|(d1, _), (d2, _)| d2.partial_cmp(d1).unwrap());
         let names: Vec<_> = sims
             .iter()
             .take(20)
             .filter(|(s, _)| s > &0.85)
-            .map(|(_, f)| f.name.clone())
+            .map(|(_, name)| name.clone())
             .collect();
         if names.len() < 10 {
-            sims.iter().take(10).map(|(_, f)| f.name.clone()).collect()
+            sims.iter().take(10).map(|(_, name)| name.clone()).collect()
         } else {
             names
         }
@@ -366,17 +453,47 @@ pub async fn st(ctx: Context<'_>, query: String) -> anyhow::Result<()> {
         .get::<SoundStorage>()
         .unwrap()
         .clone();
-    let sims = storage.read().unwrap().calc_similarities(query);
+    let macros = ctx
+        .serenity_context()
+        .data
+        .read()
+        .await
+        .get::<MacroStorage>()
+        .unwrap()
+        .clone();
+    let sims = storage.read().unwrap().calc_similarities(&query);
+    let macro_sims = macros.read().unwrap().calc_similarities(&query);
+
+    // Similarity, name, duration and last update of the best sounds and macros.
+    let mut rows: Vec<_> = sims
+        .iter()
+        .take(10)
+        .map(|(s, file)| {
+            (
+                *s,
+                file.name.clone(),
+                format!("{:.1}", file.duration().as_secs_f64()),
+                file.updated_at(),
+            )
+        })
+        .chain(
+            macro_sims
+                .into_iter()
+                .take(10)
+                .map(|(s, m)| (s, m.name, "macro".to_owned(), m.updated_at)),
+        )
+        .collect();
+    rows.sort_by(|(d1, ..), (d2, ..)| d2.partial_cmp(d1).unwrap());
 
     let mut table = Table::new();
     table.set_format(*format::consts::FORMAT_CLEAN);
     table.set_titles(row!["Name", "Dur", "Updated"]);
 
-    for (_, file) in sims.iter().take(10) {
-        let updated_at: DateTime<Utc> = file.updated_at().into();
+    for (_, name, dur, updated_at) in rows.into_iter().take(10) {
+        let updated_at: DateTime<Utc> = updated_at.into();
         table.add_row(row![
-            file.name,
-            format!("{:.1}", file.duration().as_secs_f64()),
+            name,
+            dur,
             updated_at.format("%Y-%m-%d") // updated_at.format("%Y-%m-%d %T")
         ]);
     }
@@ -416,6 +533,82 @@ pub async fn rhai(ctx: Context<'_>, #[rest] rest: String) -> anyhow::Result<()>
     Ok(())
 }
 
+/// Plays an attached MIDI file with a sound as the instrument, e.g. `~midi sainou 67`
+///
+/// The sound keeps its own pitch at the base note, which is 60 (C4) unless given.
+#[poise::command(prefix_command, guild_only)]
+pub async fn midi(
+    ctx: Context<'_>,
+    sound: String,
+    base_note: Option<u8>,
+    file: Attachment,
+) -> anyhow::Result<()> {
+    let guild_id = ctx.guild_id().context("Guild was not found")?;
+    let storage = ctx
+        .serenity_context()
+        .data
+        .read()
+        .await
+        .get::<SoundStorage>()
+        .context("Could not get SoundStorage")?
+        .clone();
+    let exists =
+        { storage.read().unwrap().get(&sound).is_some() } || Synth::from_name(&sound).is_some();
+    if !exists {
+        ctx.reply("The given sound was not found").await?;
+        return Ok(());
+    }
+
+    if file.size > MAX_MIDI_BYTES {
+        ctx.reply("The MIDI file is too large").await?;
+        return Ok(());
+    }
+    let data = file.download().await?;
+    let base_note = base_note.unwrap_or(DEFAULT_BASE_NOTE);
+    match say_commands_from_midi(&data, &sound, base_note) {
+        Ok(saycmds) => process_from_say_commands(ctx.serenity_context(), guild_id, saycmds).await,
+        Err(e) => {
+            ctx.reply(format!("Failed to read the MIDI file: {e}"))
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Renders say commands to a wav file instead of playing them, e.g. `~render a 120 | b`
+#[poise::command(prefix_command)]
+pub async fn render(ctx: Context<'_>, #[rest] rest: String) -> anyhow::Result<()> {
+    let source = rest.trim().trim_matches('`').to_owned();
+    let saycmds = match parse_say_commands(ctx.serenity_context(), &source).await? {
+        Ok(saycmds) if !saycmds.is_empty() => saycmds,
+        Ok(_) => {
+            ctx.reply("Nothing to render").await?;
+            return Ok(());
+        }
+        Err(diagnostic) => {
+            ctx.reply(format!("```\n{}\n```", diagnostic.render(&source)))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let Some(wav) =
+        render_say_commands_in_context(saycmds, ctx.serenity_context(), MAX_ATTACHMENT_BYTES)
+            .await?
+    else {
+        ctx.reply("The rendered sound is too long to attach")
+            .await?;
+        return Ok(());
+    };
+    ctx.send(
+        poise::CreateReply::default()
+            .reply(true)
+            .attachment(CreateAttachment::bytes(wav, "render.wav")),
+    )
+    .await?;
+    Ok(())
+}
+
 /// Restarts the container
 #[poise::command(prefix_command)]
 pub async fn restart(_ctx: Context<'_>) -> anyhow::Result<()> {
@@ -425,7 +618,7 @@ pub async fn restart(_ctx: Context<'_>) -> anyhow::Result<()> {
 #[tracing::instrument]
 #[poise::command(prefix_command, owners_only)]
 pub async fn upload(ctx: Context<'_>, files: Vec<Attachment>) -> anyhow::Result<()> {
-    let mut count = 0;
+    let mut uploaded = Vec::new();
     let storage = ctx
         .serenity_context()
         .data
@@ -456,7 +649,7 @@ pub async fn upload(ctx: Context<'_>, files: Vec<Attachment>) -> anyhow::Result<
                     .join(PathBuf::from(entry.filename()).file_name().unwrap());
                 let mut writer = tokio::fs::File::create(&out_path).await?;
                 tokio::io::copy(&mut entry_reader, &mut writer).await?;
-                count += 1;
+                uploaded.push(out_path.file_stem().unwrap().to_string_lossy().into_owned());
 
                 let mut file = tokio::fs::File::open(&out_path).await?;
                 let mut content = vec![];
@@ -478,7 +671,7 @@ pub async fn upload(ctx: Context<'_>, files: Vec<Attachment>) -> anyhow::Result<
             let out_path = storage.read().unwrap().dir.join(&attachment.filename);
             let mut file = tokio::fs::File::create(&out_path).await?;
             file.write_all(&content).await?;
-            count += 1;
+            uploaded.push(out_path.file_stem().unwrap().to_string_lossy().into_owned());
 
             client
                 .object()
@@ -495,13 +688,14 @@ pub async fn upload(ctx: Context<'_>, files: Vec<Attachment>) -> anyhow::Result<
         }
     }
 
-    tokio::spawn(update_sounds_bin(storage.read().unwrap().dir.clone()));
+    let macros = list_macros(ctx.serenity_context()).await?;
+    tokio::spawn(update_sounds_bin(storage.read().unwrap().clone(), macros));
 
     storage.write().unwrap().reload();
 
-    clean_cache_inner(ctx.serenity_context()).await?;
+    invalidate_cache(ctx.serenity_context(), &uploaded).await?;
 
-    ctx.reply(format!("Successfully uploaded {count} sounds"))
+    ctx.reply(format!("Successfully uploaded {} sounds", uploaded.len()))
         .await
         .ok();
     Ok(())
@@ -534,9 +728,10 @@ pub async fn delete(ctx: Context<'_>, #[rest] rest: String) -> anyhow::Result<()
         }
     }
 
-    tokio::spawn(update_sounds_bin(storage.read().unwrap().dir.clone()));
+    let macros = list_macros(ctx.serenity_context()).await?;
+    tokio::spawn(update_sounds_bin(storage.read().unwrap().clone(), macros));
 
-    clean_cache_inner(ctx.serenity_context()).await?;
+    invalidate_cache(ctx.serenity_context(), &deleted).await?;
 
     if deleted.is_empty() {
         ctx.reply("The given saysounds were not found").await.ok();
@@ -548,6 +743,116 @@ pub async fn delete(ctx: Context<'_>, #[rest] rest: String) -> anyhow::Result<()
     Ok(())
 }
 
+async fn list_macros(ctx: &SerenityContext) -> anyhow::Result<Vec<Macro>> {
+    let macros = ctx
+        .data
+        .read()
+        .await
+        .get::<MacroStorage>()
+        .context("Could not get MacroStorage")?
+        .clone();
+    let macros = macros.read().unwrap().macros();
+    Ok(macros)
+}
+
+/// Manages macros, named say commands that can be used like sounds
+#[poise::command(
+    prefix_command,
+    rename = "macro",
+    subcommands("macro_set", "macro_remove", "macro_show")
+)]
+pub async fn macro_(_ctx: Context<'_>) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Defines a macro, e.g. `~macro set intro "a 120; b p80 | c"`
+#[poise::command(prefix_command, rename = "set", owners_only)]
+pub async fn macro_set(ctx: Context<'_>, name: String, #[rest] body: String) -> anyhow::Result<()> {
+    let storage = ctx
+        .serenity_context()
+        .data
+        .read()
+        .await
+        .get::<SoundStorage>()
+        .context("Could not get SoundStorage")?
+        .clone();
+    let macros = ctx
+        .serenity_context()
+        .data
+        .read()
+        .await
+        .get::<MacroStorage>()
+        .context("Could not get MacroStorage")?
+        .clone();
+    let body = body.trim().trim_matches('"');
+    let result =
+        macros
+            .write()
+            .unwrap()
+            .set(&name, body, ctx.author().id, &storage.read().unwrap());
+    match result {
+        Ok(()) => {
+            let macros = list_macros(ctx.serenity_context()).await?;
+            tokio::spawn(update_sounds_bin(storage.read().unwrap().clone(), macros));
+            ctx.reply(format!("Set {name}: {body}")).await?;
+        }
+        Err(e) => {
+            ctx.reply(format!("```\n{e}\n```")).await?;
+        }
+    }
+    Ok(())
+}
+
+#[poise::command(prefix_command, rename = "remove", owners_only)]
+pub async fn macro_remove(ctx: Context<'_>, name: String) -> anyhow::Result<()> {
+    let storage = ctx
+        .serenity_context()
+        .data
+        .read()
+        .await
+        .get::<SoundStorage>()
+        .context("Could not get SoundStorage")?
+        .clone();
+    let macros = ctx
+        .serenity_context()
+        .data
+        .read()
+        .await
+        .get::<MacroStorage>()
+        .context("Could not get MacroStorage")?
+        .clone();
+    let removed = { macros.write().unwrap().remove(&name)? };
+    if removed {
+        let macros = list_macros(ctx.serenity_context()).await?;
+        tokio::spawn(update_sounds_bin(storage.read().unwrap().clone(), macros));
+        ctx.reply(format!("Removed {name}")).await?;
+    } else {
+        ctx.reply("The given macro was not found").await?;
+    }
+    Ok(())
+}
+
+#[poise::command(prefix_command, rename = "show")]
+pub async fn macro_show(ctx: Context<'_>, name: String) -> anyhow::Result<()> {
+    let macros = ctx
+        .serenity_context()
+        .data
+        .read()
+        .await
+        .get::<MacroStorage>()
+        .context("Could not get MacroStorage")?
+        .clone();
+    let r#macro = { macros.read().unwrap().get(&name) };
+    match r#macro {
+        Some(r#macro) => {
+            ctx.say(format!("{}: {}", r#macro.name, r#macro.body))
+                .await?
+        }
+        None => ctx.reply("The given macro was not found").await?,
+    };
+    Ok(())
+}
+
 #[allow(clippy::single_match)]
 #[poise::command(prefix_command, guild_only)]
 pub async fn config(
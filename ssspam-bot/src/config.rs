@@ -11,6 +11,17 @@ use serenity::{
     prelude::TypeMapKey,
 };
 
+use crate::core::PlaybackPolicy;
+
+/// Level in the voice channel that messages are limited to unless configured otherwise.
+pub const DEFAULT_CLIP_THRESHOLD: f32 = 0.01;
+
+/// How abruptly the limiter sets in unless configured otherwise.
+pub const DEFAULT_SHARPNESS: f32 = 250.0;
+
+/// Loudness sounds are played at unless configured otherwise, in LUFS.
+pub const DEFAULT_LOUDNESS_TARGET: f64 = -16.0;
+
 pub struct Configs {
     db: PickleDb,
 }
@@ -33,27 +44,66 @@ impl Configs {
         Ok(Self { db })
     }
 
+    /// Level in the voice channel that the limiter keeps every sample of a message below.
+    /// Messages are limited one by one, so ones overlapping in the voice channel can exceed it
+    /// together.
     pub fn get_clip_threshold(&self) -> f32 {
-        self.db.get::<f32>("global.clip_threshold").unwrap_or(0.01)
+        self.db
+            .get::<f32>("global.clip_threshold")
+            .unwrap_or(DEFAULT_CLIP_THRESHOLD)
     }
 
     // TODO: Generic values
     pub fn set_clip_threshold(&mut self, value: &str) -> anyhow::Result<()> {
+        let value = value.parse::<f32>()?;
+        if !value.is_finite() || value <= 0.0 {
+            bail!("clip_threshold must be positive");
+        }
         self.db
-            .set("global.clip_threshold", &value.parse::<f32>()?)
+            .set("global.clip_threshold", &value)
             .context("Failed to set clip_threshold")
     }
 
+    /// How abruptly the limiter sets in around the clip threshold.
     pub fn get_sharpness(&self) -> f32 {
-        self.db.get::<f32>("global.sharpness").unwrap_or(250.0)
+        self.db
+            .get::<f32>("global.sharpness")
+            .unwrap_or(DEFAULT_SHARPNESS)
     }
 
     pub fn set_sharpness(&mut self, value: &str) -> anyhow::Result<()> {
+        let value = value.parse::<f32>()?;
+        if !value.is_finite() || value <= 0.0 {
+            bail!("sharpness must be positive");
+        }
         self.db
-            .set("global.sharpness", &value.parse::<f32>()?)
+            .set("global.sharpness", &value)
             .context("Failed to set sharpness")
     }
 
+    /// Loudness in LUFS that every sound is brought towards when played.
+    pub fn get_loudness_target(&self) -> f64 {
+        self.db
+            .get::<f64>("global.loudness_target")
+            .unwrap_or(DEFAULT_LOUDNESS_TARGET)
+    }
+
+    pub fn set_loudness_target(&mut self, value: &str) -> anyhow::Result<()> {
+        let value = value.parse::<f64>()?;
+        if !(-70.0..=0.0).contains(&value) {
+            bail!("loudness_target must be between -70 and 0");
+        }
+        self.db
+            .set("global.loudness_target", &value)
+            .context("Failed to set loudness_target")
+    }
+
+    pub fn remove_loudness_target(&mut self) -> anyhow::Result<bool> {
+        self.db
+            .rem("global.loudness_target")
+            .context("Failed to remove loudness_target")
+    }
+
     pub fn get_joinsound(&self, user_id: &UserId) -> Option<String> {
         self.db
             .get::<String>(&format!("users.u{user_id}.joinsound"))
@@ -88,10 +138,57 @@ impl Configs {
             .context("Faield to remove joinsound")
     }
 
-    pub fn get(&self, _guild_id: &GuildId, key: &str, user_id: &UserId) -> Option<String> {
+    /// Whether the bot replies with parse diagnostics to messages that look like say commands.
+    pub fn get_diagnostics(&self, guild_id: &GuildId) -> bool {
+        self.db
+            .get::<bool>(&format!("guilds.g{guild_id}.diagnostics"))
+            .unwrap_or(false)
+    }
+
+    pub fn set_diagnostics(&mut self, guild_id: &GuildId, value: &str) -> anyhow::Result<()> {
+        self.db
+            .set(
+                &format!("guilds.g{guild_id}.diagnostics"),
+                &value.parse::<bool>()?,
+            )
+            .context("Failed to set diagnostics")
+    }
+
+    pub fn remove_diagnostics(&mut self, guild_id: &GuildId) -> anyhow::Result<bool> {
+        self.db
+            .rem(&format!("guilds.g{guild_id}.diagnostics"))
+            .context("Failed to remove diagnostics")
+    }
+
+    /// How say commands are played while others are still playing in the guild.
+    pub fn get_playback(&self, guild_id: &GuildId) -> PlaybackPolicy {
+        self.db
+            .get::<PlaybackPolicy>(&format!("guilds.g{guild_id}.playback"))
+            .unwrap_or_default()
+    }
+
+    pub fn set_playback(&mut self, guild_id: &GuildId, value: &str) -> anyhow::Result<()> {
+        self.db
+            .set(
+                &format!("guilds.g{guild_id}.playback"),
+                &value.parse::<PlaybackPolicy>()?,
+            )
+            .context("Failed to set playback")
+    }
+
+    pub fn remove_playback(&mut self, guild_id: &GuildId) -> anyhow::Result<bool> {
+        self.db
+            .rem(&format!("guilds.g{guild_id}.playback"))
+            .context("Failed to remove playback")
+    }
+
+    pub fn get(&self, guild_id: &GuildId, key: &str, user_id: &UserId) -> Option<String> {
         match key {
             "clip_threshold" => Some(self.get_clip_threshold().to_string()),
             "sharpness" => Some(self.get_sharpness().to_string()),
+            "loudness_target" => Some(self.get_loudness_target().to_string()),
+            "diagnostics" => Some(self.get_diagnostics(guild_id).to_string()),
+            "playback" => Some(self.get_playback(guild_id).to_string()),
             "joinsound" => self.get_joinsound(user_id),
             "leavesound" => self.get_leavesound(user_id),
             _ => None,
@@ -100,7 +197,7 @@ impl Configs {
 
     pub fn set(
         &mut self,
-        _guild_id: &GuildId,
+        guild_id: &GuildId,
         key: &str,
         value: &str,
         user_id: &UserId,
@@ -108,6 +205,9 @@ impl Configs {
         match key {
             "clip_threshold" => self.set_clip_threshold(value),
             "sharpness" => self.set_sharpness(value),
+            "loudness_target" => self.set_loudness_target(value),
+            "diagnostics" => self.set_diagnostics(guild_id, value),
+            "playback" => self.set_playback(guild_id, value),
             "joinsound" => self.set_joinsound(user_id, value),
             "leavesound" => self.set_leavesound(user_id, value),
             _ => bail!("Unrecognized key"),
@@ -116,11 +216,14 @@ impl Configs {
 
     pub fn remove(
         &mut self,
-        _guild_id: &GuildId,
+        guild_id: &GuildId,
         key: &str,
         user_id: &UserId,
     ) -> anyhow::Result<bool> {
         match key {
+            "loudness_target" => self.remove_loudness_target(),
+            "diagnostics" => self.remove_diagnostics(guild_id),
+            "playback" => self.remove_playback(guild_id),
             "joinsound" => self.remove_joinsound(user_id),
             "leavesound" => self.remove_leavesound(user_id),
             _ => bail!("Unrecognized key"),
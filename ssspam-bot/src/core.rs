@@ -3,10 +3,13 @@ use std::{
     fs,
     path::PathBuf,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
-use anyhow::Context as _;
+use anyhow::{Context as _, bail};
 use dashmap::{DashMap, DashSet};
 use serde::{Deserialize, Serialize};
 use serenity::{
@@ -19,12 +22,17 @@ use serenity::{
     prelude::TypeMapKey,
 };
 use tokio::sync::{
-    broadcast,
+    MutexGuard, broadcast,
     broadcast::{Receiver, Sender},
+    oneshot,
 };
-use tracing::Instrument;
+use tracing::{Instrument, warn};
 
-use crate::{SayCommands, play_say_commands};
+use crate::{
+    Configs, MacroStorage, SayCommands, SoundStorage, play_say_commands,
+    sslang::{Diagnostic, DiagnosticKind, leading_sound_name},
+    synth::Synth,
+};
 
 /// Keeps track of channels where the bot joining.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -169,6 +177,201 @@ impl TypeMapKey for GuildBroadcast {
     type Value = Arc<Mutex<Self>>;
 }
 
+/// How say commands submitted while others are still playing in the same guild are played.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackPolicy {
+    /// Play at once, on top of whatever is playing. Each message is limited on its own, so
+    /// overlapping messages can together be louder than the clip threshold.
+    #[default]
+    Overlap,
+    /// Wait until everything submitted earlier has finished playing.
+    Queue,
+    /// Stop whatever is playing first.
+    Interrupt,
+}
+
+impl FromStr for PlaybackPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overlap" => Ok(Self::Overlap),
+            "queue" => Ok(Self::Queue),
+            "interrupt" => Ok(Self::Interrupt),
+            _ => bail!("Expected overlap, queue or interrupt"),
+        }
+    }
+}
+
+impl std::fmt::Display for PlaybackPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overlap => write!(f, "overlap"),
+            Self::Queue => write!(f, "queue"),
+            Self::Interrupt => write!(f, "interrupt"),
+        }
+    }
+}
+
+/// Orders the say commands played in each guild according to its [`PlaybackPolicy`].
+#[derive(Debug, Default)]
+pub struct PlaybackCoordinator {
+    guilds: DashMap<GuildId, Arc<GuildPlayback>>,
+}
+
+impl PlaybackCoordinator {
+    /// Plays `saycmds` in `guild_id` once `policy` allows it, until they end or an
+    /// [`OpsMessage::Stop`] is sent on `stops`.
+    ///
+    /// [`PlaybackPolicy::Interrupt`] is left to the caller, which also has to make sure that
+    /// stopping the previous say commands does not cancel these.
+    pub async fn play(
+        &self,
+        ctx: &Context,
+        guild_id: GuildId,
+        policy: PlaybackPolicy,
+        saycmds: SayCommands,
+        stops: &Sender<OpsMessage>,
+    ) -> anyhow::Result<()> {
+        let guild = Arc::clone(self.guilds.entry(guild_id).or_default().value());
+        let waiting = saycmds.clone();
+        let play = async {
+            let rest = play_say_commands(saycmds, ctx, guild_id).await?;
+            if policy == PlaybackPolicy::Queue {
+                // The next say commands start after the last sound has ended.
+                tokio::time::sleep(rest).await;
+            }
+            anyhow::Ok(())
+        };
+        guild
+            .play(policy, &waiting, stops, play)
+            .await
+            .unwrap_or(Ok(()))
+    }
+
+    /// Returns the say commands waiting in `guild_id`, oldest first.
+    pub fn pending(&self, guild_id: GuildId) -> Vec<SayCommands> {
+        self.guilds
+            .get(&guild_id)
+            .map(|guild| guild.pending())
+            .unwrap_or_default()
+    }
+
+    /// Drops the say commands waiting in `guild_id` and returns how many there were.
+    pub fn clear(&self, guild_id: GuildId) -> usize {
+        self.guilds.get(&guild_id).map_or(0, |guild| guild.clear())
+    }
+}
+
+impl TypeMapKey for PlaybackCoordinator {
+    type Value = Arc<Self>;
+}
+
+#[derive(Debug, Default)]
+struct GuildPlayback {
+    /// Held while say commands play under [`PlaybackPolicy::Queue`]. Tokio's mutex is fair, so
+    /// waiters get it in the order they asked for it.
+    turn: tokio::sync::Mutex<()>,
+    /// Say commands waiting for `turn`, oldest first.
+    pending: Mutex<Vec<Pending>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Pending {
+    id: u64,
+    saycmds: SayCommands,
+    cancel: oneshot::Sender<()>,
+}
+
+impl GuildPlayback {
+    /// Runs `play` once `policy` allows it. Returns `None` if it was cancelled while waiting by
+    /// [`Self::clear`], or stopped while playing by an [`OpsMessage::Stop`] on `stops`.
+    ///
+    /// A stop only ends what is playing: say commands still waiting for their turn are not
+    /// subscribed yet, so they stay in the queue and play next.
+    async fn play<T>(
+        &self,
+        policy: PlaybackPolicy,
+        saycmds: &SayCommands,
+        stops: &Sender<OpsMessage>,
+        play: impl Future<Output = T>,
+    ) -> Option<T> {
+        let _turn = match policy {
+            PlaybackPolicy::Queue => Some(self.wait_for_turn(saycmds).await?),
+            PlaybackPolicy::Overlap | PlaybackPolicy::Interrupt => None,
+        };
+        let mut rx = stops.subscribe();
+        tokio::select! {
+            output = play => Some(output),
+            _ = async move {
+                while let Ok(msg) = rx.recv().await {
+                    if msg == OpsMessage::Stop {
+                        break;
+                    }
+                }
+            } => None,
+        }
+    }
+
+    /// Waits until everything submitted earlier has played, or returns `None` if the wait is
+    /// cancelled by [`Self::clear`].
+    async fn wait_for_turn(&self, saycmds: &SayCommands) -> Option<MutexGuard<'_, ()>> {
+        if let Ok(turn) = self.turn.try_lock() {
+            return Some(turn);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (cancel, cancelled) = oneshot::channel();
+        self.pending.lock().unwrap().push(Pending {
+            id,
+            saycmds: saycmds.clone(),
+            cancel,
+        });
+        // Also removes the entry when the wait itself is dropped.
+        let _entry = PendingEntry { guild: self, id };
+        tokio::select! {
+            turn = self.turn.lock() => Some(turn),
+            _ = cancelled => None,
+        }
+    }
+
+    fn pending(&self) -> Vec<SayCommands> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|pending| pending.saycmds.clone())
+            .collect()
+    }
+
+    fn clear(&self) -> usize {
+        let cleared: Vec<_> = self.pending.lock().unwrap().drain(..).collect();
+        let count = cleared.len();
+        for pending in cleared {
+            pending.cancel.send(()).ok();
+        }
+        count
+    }
+}
+
+/// Removes a [`Pending`] entry however its wait ends.
+struct PendingEntry<'a> {
+    guild: &'a GuildPlayback,
+    id: u64,
+}
+
+impl Drop for PendingEntry<'_> {
+    fn drop(&mut self) {
+        self.guild
+            .pending
+            .lock()
+            .unwrap()
+            .retain(|pending| pending.id != self.id);
+    }
+}
+
 #[tracing::instrument(skip_all)]
 pub async fn process_message(ctx: &Context, msg: &Message) -> anyhow::Result<()> {
     let get_guild_span = tracing::info_span!("get_guild");
@@ -212,38 +415,136 @@ pub async fn process_message(ctx: &Context, msg: &Message) -> anyhow::Result<()>
         return Ok(());
     }
 
-    let saycmds = {
-        let mut saycmds = match SayCommands::from_str(&msg.content) {
-            Ok(saycmds) => saycmds,
-            // A parse failure does not imply an error because normal messages also exist.
-            Err(_) => return Ok(()),
-        };
-        if saycmds.is_empty() {
-            return Ok(());
-        }
-        saycmds.sanitize();
-        saycmds
+    let storage = ctx
+        .data
+        .read()
+        .await
+        .get::<SoundStorage>()
+        .context("Could not get SoundStorage")?
+        .clone();
+    let macros = ctx
+        .data
+        .read()
+        .await
+        .get::<MacroStorage>()
+        .context("Could not get MacroStorage")?
+        .clone();
+    // Most chat parses as say commands, so it is left alone unless it names something playable.
+    if !names_known_sound(
+        &msg.content,
+        &storage.read().unwrap(),
+        &macros.read().unwrap(),
+    ) {
+        return Ok(());
+    }
+
+    let parsed = parse_say_commands(ctx, &msg.content).await?;
+    if let Err(e) = report_diagnostics(ctx, msg, guild.id, &parsed).await {
+        warn!("Error while reporting diagnostics: {e:?}");
+    }
+
+    match parsed {
+        Ok(saycmds) => process_from_say_commands(ctx, guild.id, saycmds).await,
+        // A parse failure does not imply an error because normal messages also exist.
+        Err(_) => Ok(()),
+    }
+}
+
+/// Returns whether `source` names a sound, macro or synthetic sound, which sets say commands apart
+/// from ordinary chat that happens to parse as them.
+fn names_known_sound(source: &str, storage: &SoundStorage, macros: &MacroStorage) -> bool {
+    let known = |name: &str| {
+        Synth::from_name(name).is_some()
+            || storage.get_marked(name).is_some()
+            || macros.get(name).is_some()
     };
+    match SayCommands::from_str(source) {
+        Ok(saycmds) => saycmds.mentions(&known),
+        // Say commands with a mistake in them still start with the sound meant.
+        Err(_) => leading_sound_name(source).is_some_and(known),
+    }
+}
 
-    let guild_broadcast = ctx
+/// Parses `source` and expands the macros used in it.
+pub async fn parse_say_commands(
+    ctx: &Context,
+    source: &str,
+) -> anyhow::Result<Result<SayCommands, Diagnostic>> {
+    let storage = ctx
         .data
         .read()
         .await
-        .get::<GuildBroadcast>()
-        .context("Could not get GuildBroadcast")?
+        .get::<SoundStorage>()
+        .context("Could not get SoundStorage")?
         .clone();
-    let mut rx = guild_broadcast.lock().unwrap().subscribe(guild.id);
-
-    tokio::select! {
-        res = play_say_commands(saycmds, ctx, guild.id) => res,
-        _ = async move {
-            while let Ok(msg) = rx.recv().await {
-                if msg == OpsMessage::Stop {
-                    break;
-                }
-            }
-        } => Ok(())
+    let macros = ctx
+        .data
+        .read()
+        .await
+        .get::<MacroStorage>()
+        .context("Could not get MacroStorage")?
+        .clone();
+    Ok(SayCommands::from_str(source).and_then(|mut saycmds| {
+        macros
+            .read()
+            .unwrap()
+            .expand(&mut saycmds, &storage.read().unwrap())?;
+        Ok(saycmds)
+    }))
+}
+
+/// Replies with parse and unknown-sound diagnostics when the guild opted in. `msg` is expected
+/// to name something playable, so that ordinary chat is not answered.
+///
+/// Rejected audio filters and broken macros are always reported: neither `af=` nor a macro
+/// name is ordinary chat, and the user would otherwise not know why nothing was played.
+#[tracing::instrument(skip_all)]
+async fn report_diagnostics(
+    ctx: &Context,
+    msg: &Message,
+    guild_id: GuildId,
+    parsed: &Result<SayCommands, Diagnostic>,
+) -> anyhow::Result<()> {
+    let configs = ctx
+        .data
+        .read()
+        .await
+        .get::<Configs>()
+        .context("Could not get Configs")?
+        .clone();
+    let always_reported = matches!(
+        parsed,
+        Err(Diagnostic {
+            kind: DiagnosticKind::RejectedAudioFilter { .. } | DiagnosticKind::Macro { .. },
+            ..
+        })
+    );
+    if !always_reported && !configs.read().unwrap().get_diagnostics(&guild_id) {
+        return Ok(());
     }
+
+    let storage = ctx
+        .data
+        .read()
+        .await
+        .get::<SoundStorage>()
+        .context("Could not get SoundStorage")?
+        .clone();
+    let diagnostics = match parsed {
+        Err(diagnostic) => vec![diagnostic.clone()],
+        Ok(saycmds) => saycmds.unknown_sounds(&storage.read().unwrap()),
+    };
+    if diagnostics.is_empty() {
+        return Ok(());
+    }
+
+    let rendered: Vec<_> = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.render(&msg.content))
+        .collect();
+    msg.reply(ctx, format!("```\n{}\n```", rendered.join("\n")))
+        .await?;
+    Ok(())
 }
 
 #[tracing::instrument]
@@ -252,18 +553,92 @@ pub async fn process_from_string(
     guild_id: GuildId,
     sound: &str,
 ) -> anyhow::Result<()> {
-    let saycmds = {
-        let mut saycmds = match SayCommands::from_str(sound) {
-            Ok(saycmds) => saycmds,
-            // A parse failure does not imply an error because normal messages also exist.
-            Err(_) => return Ok(()),
-        };
-        if saycmds.is_empty() {
-            return Ok(());
-        }
-        saycmds.sanitize();
-        saycmds
+    match parse_say_commands(ctx, sound).await? {
+        Ok(saycmds) => process_from_say_commands(ctx, guild_id, saycmds).await,
+        // A parse failure does not imply an error because normal messages also exist.
+        Err(_) => Ok(()),
+    }
+}
+
+/// Plays `saycmds` in `guild_id` following its [`PlaybackPolicy`] until they end or `~stop` is
+/// used.
+#[tracing::instrument]
+pub async fn process_from_say_commands(
+    ctx: &Context,
+    guild_id: GuildId,
+    mut saycmds: SayCommands,
+) -> anyhow::Result<()> {
+    if saycmds.is_empty() {
+        return Ok(());
+    }
+    saycmds.sanitize();
+
+    let storage = ctx
+        .data
+        .read()
+        .await
+        .get::<SoundStorage>()
+        .context("Could not get SoundStorage")?
+        .clone();
+    if !resolve_playable(&mut saycmds, &storage.read().unwrap()) {
+        return Ok(());
+    }
+
+    let configs = ctx
+        .data
+        .read()
+        .await
+        .get::<Configs>()
+        .context("Could not get Configs")?
+        .clone();
+    let policy = configs.read().unwrap().get_playback(&guild_id);
+    if policy == PlaybackPolicy::Interrupt {
+        // Stopping before subscribing keeps the stop from also cancelling these commands.
+        stop_playback(ctx, guild_id).await?;
+    }
+
+    let coordinator = ctx
+        .data
+        .read()
+        .await
+        .get::<PlaybackCoordinator>()
+        .context("Could not get PlaybackCoordinator")?
+        .clone();
+    let guild_broadcast = ctx
+        .data
+        .read()
+        .await
+        .get::<GuildBroadcast>()
+        .context("Could not get GuildBroadcast")?
+        .clone();
+    let stops = guild_broadcast.lock().unwrap().get_sender(guild_id);
+
+    coordinator
+        .play(ctx, guild_id, policy, saycmds, &stops)
+        .await
+}
+
+/// Resolves the choices of `saycmds` and returns whether any sound or synthetic sound is left to
+/// play. Chat that merely parses as say commands must neither interrupt nor wait in the queue.
+fn resolve_playable(saycmds: &mut SayCommands, storage: &SoundStorage) -> bool {
+    saycmds.resolve_choices(storage, None);
+    saycmds
+        .iter()
+        .any(|cmd| Synth::from_name(&cmd.name).is_some() || storage.get_marked(&cmd.name).is_some())
+}
+
+/// Stops every sound playing in `guild_id`, along with the say commands being played there.
+/// Say commands waiting in the queue are kept and play next; `~queue clear` drops them.
+/// Returns `false` if the bot is not in a voice channel of the guild.
+pub async fn stop_playback(ctx: &Context, guild_id: GuildId) -> anyhow::Result<bool> {
+    let manager = songbird::get(ctx)
+        .await
+        .context("Songbird Voice client placed in at initialization.")?
+        .clone();
+    let Some(handler_lock) = manager.get(guild_id) else {
+        return Ok(false);
     };
+    handler_lock.lock().await.stop();
 
     let guild_broadcast = ctx
         .data
@@ -272,16 +647,144 @@ pub async fn process_from_string(
         .get::<GuildBroadcast>()
         .context("Could not get GuildBroadcast")?
         .clone();
-    let mut rx = guild_broadcast.lock().unwrap().subscribe(guild_id);
-
-    tokio::select! {
-        res = play_say_commands(saycmds, ctx, guild_id) => res,
-        _ = async move {
-            while let Ok(msg) = rx.recv().await {
-                if msg == OpsMessage::Stop {
-                    break;
-                }
-            }
-        } => Ok(())
+    let tx = guild_broadcast.lock().unwrap().get_sender(guild_id);
+    tx.send(OpsMessage::Stop)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_guild_playback_queue() {
+        let guild = Arc::new(GuildPlayback::default());
+        let playing = guild
+            .wait_for_turn(&SayCommands::from_str("a").unwrap())
+            .await
+            .unwrap();
+
+        let submit = |source: &'static str| {
+            let guild = Arc::clone(&guild);
+            tokio::spawn(async move {
+                let saycmds = SayCommands::from_str(source).unwrap();
+                guild.wait_for_turn(&saycmds).await.is_some()
+            })
+        };
+        let b = submit("b");
+        let c = submit("c 120");
+        while guild.pending().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            guild
+                .pending()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["b", "c 120"]
+        );
+
+        assert_eq!(guild.clear(), 2);
+        assert!(!b.await.unwrap());
+        assert!(!c.await.unwrap());
+        assert!(guild.pending().is_empty());
+
+        let d = submit("d");
+        while guild.pending().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        drop(playing);
+        assert!(d.await.unwrap());
+        assert!(guild.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stop_keeps_pending() {
+        let guild = Arc::new(GuildPlayback::default());
+        let (stops, _rx) = broadcast::channel(16);
+        let submit = |source: &'static str| {
+            let guild = Arc::clone(&guild);
+            let stops = stops.clone();
+            tokio::spawn(async move {
+                let saycmds = SayCommands::from_str(source).unwrap();
+                guild
+                    .play(
+                        PlaybackPolicy::Queue,
+                        &saycmds,
+                        &stops,
+                        std::future::pending::<()>(),
+                    )
+                    .await
+                    .is_some()
+            })
+        };
+
+        let a = submit("a");
+        while guild.turn.try_lock().is_ok() {
+            tokio::task::yield_now().await;
+        }
+        let b = submit("b");
+        while guild.pending().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // Stopping ends what is playing, and the say commands waiting play next.
+        stops.send(OpsMessage::Stop).unwrap();
+        assert!(!a.await.unwrap());
+        while !guild.pending().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert!(!b.is_finished());
+
+        stops.send(OpsMessage::Stop).unwrap();
+        assert!(!b.await.unwrap());
+    }
+
+    #[test]
+    fn test_names_known_sound() {
+        let storage = SoundStorage::load(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("..")
+                .join("tests/sound"),
+        );
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut macros = MacroStorage::load_or_create(temp_dir.path().join("macros.json")).unwrap();
+        macros
+            .set("いつもの", "sainou; d", UserId::new(1), &storage)
+            .unwrap();
+        let known = |source: &str| names_known_sound(source, &storage, &macros);
+
+        // Ordinary chat parses, but names nothing playable.
+        assert!(!known("今日は雨"));
+        assert!(!known("ok"));
+        assert!(!known("ＡＢＣ 123"));
+
+        assert!(known("ＳＡＩＮＯＵ"));
+        assert!(known("SAINOU#intro; 今日は雨"));
+        assert!(known("イツモノ"));
+        assert!(known("sine440"));
+        // Mistakes after a known sound are still reported.
+        assert!(known("sainou v"));
+    }
+
+    #[test]
+    fn test_resolve_playable() {
+        let storage = SoundStorage::load(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("..")
+                .join("tests/sound"),
+        );
+        let playable =
+            |source: &str| resolve_playable(&mut SayCommands::from_str(source).unwrap(), &storage);
+
+        // Chat naming nothing known leaves whatever is playing or queued alone.
+        assert!(!playable("こんにちは; nosuchsound | {foo, bar}"));
+        assert!(!playable("sainou#nothing"));
+
+        assert!(playable("nosuchsound; sainou"));
+        assert!(playable("ＳＡＩＮＯＵ#intro"));
+        assert!(playable("sine440 d0.5"));
+        assert!(playable("{*}"));
     }
 }
@@ -0,0 +1,800 @@
+//! In-process audio processing: decoding sound files, resampling, time-stretching and the
+//! effects that say commands apply.
+
+use std::{
+    f64::consts::{FRAC_1_SQRT_2, PI},
+    io,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::DecoderOptions,
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::audio_filter::{AudioFilter, Filter};
+
+/// Length of a WSOLA segment.
+const STRETCH_WINDOW: Duration = Duration::from_millis(40);
+
+/// How far a WSOLA segment may be moved to line it up with the previous one.
+const STRETCH_TOLERANCE: Duration = Duration::from_millis(10);
+
+/// Only every n-th sample is compared when lining up WSOLA segments.
+const STRETCH_CORRELATION_STRIDE: usize = 4;
+
+/// Length of the blocks loudness is measured in.
+const LOUDNESS_BLOCK: Duration = Duration::from_millis(400);
+
+/// Blocks quieter than this are silence and do not count towards the loudness.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Blocks this much quieter than the loudness of the audible ones do not count either.
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// How long the limiter takes to let the gain recover by a factor of e once a peak has passed.
+const LIMITER_RELEASE: Duration = Duration::from_millis(100);
+
+/// Length of the header of the files written by [`Audio::to_wav`] and [`Audio::to_pcm16_wav`].
+pub const WAV_HEADER_BYTES: usize = 44;
+
+/// Interleaved 32-bit float samples.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Audio {
+    pub sample_rate_hz: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>,
+}
+
+impl Audio {
+    pub fn new(sample_rate_hz: u32, channels: usize, samples: Vec<f32>) -> Self {
+        Self {
+            sample_rate_hz,
+            channels,
+            samples,
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames() as f64 / self.sample_rate_hz as f64)
+    }
+
+    /// Number of frames in `duration`, at most the whole audio.
+    fn frames_in(&self, duration: Duration) -> usize {
+        ((duration.as_secs_f64() * self.sample_rate_hz as f64).round() as usize).min(self.frames())
+    }
+
+    /// Keeps `duration` (or everything) from `start` on.
+    pub fn trim(&mut self, start: Duration, duration: Option<Duration>) {
+        let start = self.frames_in(start);
+        self.samples.drain(..start * self.channels);
+        if let Some(duration) = duration {
+            self.truncate(duration);
+        }
+    }
+
+    /// Cuts the audio off after `duration`.
+    pub fn truncate(&mut self, duration: Duration) {
+        let frames = self.frames_in(duration);
+        self.samples.truncate(frames * self.channels);
+    }
+
+    pub fn reverse(&mut self) {
+        self.samples = self
+            .samples
+            .chunks_exact(self.channels)
+            .rev()
+            .flatten()
+            .copied()
+            .collect();
+    }
+
+    /// Fades in linearly over `duration`.
+    pub fn fade_in(&mut self, duration: Duration) {
+        let frames = self.frames_in(duration);
+        for (i, frame) in self
+            .samples
+            .chunks_exact_mut(self.channels)
+            .take(frames)
+            .enumerate()
+        {
+            let gain = i as f32 / frames as f32;
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+    }
+
+    /// Fades out linearly over the last `duration`.
+    pub fn fade_out(&mut self, duration: Duration) {
+        let frames = self.frames_in(duration);
+        let channels = self.channels;
+        for (i, frame) in self
+            .samples
+            .chunks_exact_mut(channels)
+            .rev()
+            .take(frames)
+            .enumerate()
+        {
+            let gain = i as f32 / frames as f32;
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+    }
+
+    pub fn gain(&mut self, gain: f32) {
+        self.samples.iter_mut().for_each(|s| *s *= gain);
+    }
+
+    /// Moves the audio to `position`, from -1.0 (left) to 1.0 (right). Mono audio is upmixed
+    /// first, then the opposite side is attenuated.
+    pub fn pan(&mut self, position: f64) {
+        if self.channels == 1 {
+            self.samples = self.samples.iter().flat_map(|&s| [s, s]).collect();
+            self.channels = 2;
+        }
+        let left = (1.0 - position).min(1.0) as f32;
+        let right = (1.0 + position).min(1.0) as f32;
+        for frame in self.samples.chunks_exact_mut(self.channels) {
+            frame[0] *= left;
+            frame[1] *= right;
+        }
+    }
+
+    /// Averages the channels.
+    fn mono(&self) -> Vec<f32> {
+        self.samples
+            .chunks_exact(self.channels)
+            .map(|frame| frame.iter().sum::<f32>() / self.channels as f32)
+            .collect()
+    }
+
+    /// Encodes the audio as a 32-bit float wav file.
+    pub fn to_wav(&self) -> Vec<u8> {
+        // WAVE_FORMAT_IEEE_FLOAT
+        let mut wav = self.wav_header(3, 32);
+        for sample in &self.samples {
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+        wav
+    }
+
+    /// Encodes the audio as a 16-bit wav file, which any player can open at half the size.
+    /// Samples beyond full scale are clipped.
+    pub fn to_pcm16_wav(&self) -> Vec<u8> {
+        // WAVE_FORMAT_PCM
+        let mut wav = self.wav_header(1, 16);
+        for sample in &self.samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+        wav
+    }
+
+    fn wav_header(&self, format: u16, bits_per_sample: u16) -> Vec<u8> {
+        let block_align = self.channels as u16 * bits_per_sample / 8;
+        let data_len = (self.frames() * block_align as usize) as u32;
+
+        let mut wav = Vec::with_capacity(WAV_HEADER_BYTES + data_len as usize);
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(WAV_HEADER_BYTES as u32 - 8 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&format.to_le_bytes());
+        wav.extend_from_slice(&(self.channels as u16).to_le_bytes());
+        wav.extend_from_slice(&self.sample_rate_hz.to_le_bytes());
+        wav.extend_from_slice(&(self.sample_rate_hz * block_align as u32).to_le_bytes());
+        wav.extend_from_slice(&block_align.to_le_bytes());
+        wav.extend_from_slice(&bits_per_sample.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav
+    }
+}
+
+/// Decodes the whole file at `path`.
+pub fn decode_file(path: &Path) -> anyhow::Result<Audio> {
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let file = std::fs::File::open(path).with_context(|| format!("Could not open {path:?}"))?;
+    decode(Box::new(file), &hint)
+}
+
+/// Decodes a whole stream in any format symphonia was built with.
+pub fn decode(source: Box<dyn MediaSource>, hint: &Hint) -> anyhow::Result<Audio> {
+    let stream = MediaSourceStream::new(source, Default::default());
+    let format_options = FormatOptions {
+        // Drops the encoder delay and padding so the sound starts and ends where it should.
+        enable_gapless: true,
+        ..Default::default()
+    };
+    let mut format = symphonia::default::get_probe()
+        .format(hint, stream, &format_options, &MetadataOptions::default())?
+        .format;
+    let track = format.default_track().context("No audio track")?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut audio = Audio::new(
+        track.codec_params.sample_rate.unwrap_or_default(),
+        track.codec_params.channels.map_or(1, |ch| ch.count()),
+        Vec::new(),
+    );
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt frame is skipped rather than failing the whole sound.
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        audio.sample_rate_hz = spec.rate;
+        audio.channels = spec.channels.count();
+        let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buf.copy_interleaved_ref(decoded);
+        audio.samples.extend_from_slice(buf.samples());
+    }
+    anyhow::ensure!(audio.sample_rate_hz > 0, "Unknown sample rate");
+    Ok(audio)
+}
+
+/// Resamples `audio`, whose samples are taken to be `from_rate_hz` apart, to `to_rate_hz`.
+/// Playing audio at another rate than it was recorded at changes its speed and pitch together.
+pub fn resample(audio: &Audio, from_rate_hz: f64, to_rate_hz: u32) -> Audio {
+    // Input frames per output frame.
+    let step = from_rate_hz / to_rate_hz as f64;
+    let frames = (audio.frames() as f64 / step).round() as usize;
+    if (step - 1.0).abs() < 1e-9 {
+        return Audio::new(to_rate_hz, audio.channels, audio.samples.clone());
+    }
+
+    let mut input = audio.clone();
+    if step > 1.0 {
+        // Frequencies that do not fit below the new Nyquist frequency would fold back as noise.
+        for _ in 0..2 {
+            Biquad::lowpass(from_rate_hz, 0.45 * to_rate_hz as f64).process(&mut input);
+        }
+    }
+
+    let channels = audio.channels;
+    let last = input.frames().saturating_sub(1) as isize;
+    let at = |frame: isize, ch: usize| input.samples[frame.clamp(0, last) as usize * channels + ch];
+    let mut samples = Vec::with_capacity(frames * channels);
+    for i in 0..frames {
+        let pos = i as f64 * step;
+        let frame = pos.floor() as isize;
+        let t = (pos - pos.floor()) as f32;
+        for ch in 0..channels {
+            samples.push(cubic(
+                at(frame - 1, ch),
+                at(frame, ch),
+                at(frame + 1, ch),
+                at(frame + 2, ch),
+                t,
+            ));
+        }
+    }
+    Audio::new(to_rate_hz, channels, samples)
+}
+
+/// Catmull-Rom interpolation between `y1` and `y2`.
+fn cubic(y0: f32, y1: f32, y2: f32, y3: f32, t: f32) -> f32 {
+    let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+    let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c = -0.5 * y0 + 0.5 * y2;
+    ((a * t + b) * t + c) * t + y1
+}
+
+/// Plays `audio` `tempo` times as fast without changing its pitch, using WSOLA
+/// (waveform-similarity-based overlap-add).
+pub fn time_stretch(audio: &Audio, tempo: f64) -> Audio {
+    let frames = audio.frames();
+    if (tempo - 1.0).abs() < 1e-9 || frames == 0 {
+        return audio.clone();
+    }
+    let out_frames = (frames as f64 / tempo).round() as usize;
+    let rate = audio.sample_rate_hz as f64;
+    let window_len = ((STRETCH_WINDOW.as_secs_f64() * rate) as usize / 2 * 2).max(2);
+    let hop = window_len / 2;
+    let tolerance = (STRETCH_TOLERANCE.as_secs_f64() * rate) as isize;
+    let window: Vec<f32> = (0..window_len)
+        .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f64 / window_len as f64).cos()) as f32)
+        .collect();
+
+    let mono = audio.mono();
+    let channels = audio.channels;
+    let mut samples = vec![0.0; (out_frames + window_len) * channels];
+    let mut weights = vec![0.0f32; out_frames + window_len];
+    let mut previous: Option<usize> = None;
+    for out_pos in (0..out_frames).step_by(hop) {
+        let nominal = (out_pos as f64 * tempo).round() as isize;
+        let chosen = match previous {
+            None => nominal as usize,
+            // The segment is moved to where it continues the previous one most smoothly, which
+            // is judged against what followed the previous segment in the input.
+            Some(previous) => {
+                let natural = previous + hop;
+                let similarity = |candidate: usize| {
+                    (0..hop)
+                        .step_by(STRETCH_CORRELATION_STRIDE)
+                        .take_while(|j| candidate + j < frames && natural + j < frames)
+                        .map(|j| mono[candidate + j] * mono[natural + j])
+                        .sum::<f32>()
+                };
+                (nominal - tolerance..=nominal + tolerance)
+                    .filter(|&candidate| (0..frames as isize).contains(&candidate))
+                    .map(|candidate| candidate as usize)
+                    .max_by(|&a, &b| similarity(a).total_cmp(&similarity(b)))
+                    .unwrap_or_else(|| (nominal.max(0) as usize).min(frames - 1))
+            }
+        };
+        for (j, &w) in window.iter().enumerate() {
+            let src = chosen + j;
+            if src >= frames {
+                break;
+            }
+            for ch in 0..channels {
+                samples[(out_pos + j) * channels + ch] += audio.samples[src * channels + ch] * w;
+            }
+            weights[out_pos + j] += w;
+        }
+        previous = Some(chosen);
+    }
+
+    // Overlapping Hann windows add up to one, except where only one of them covers the output.
+    samples.truncate(out_frames * channels);
+    for (frame, &weight) in samples.chunks_exact_mut(channels).zip(&weights) {
+        if weight > 1e-6 {
+            frame.iter_mut().for_each(|s| *s /= weight);
+        }
+    }
+    Audio::new(audio.sample_rate_hz, channels, samples)
+}
+
+/// Second-order IIR filter from the Audio EQ Cookbook, with a Butterworth response.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn lowpass(sample_rate_hz: f64, frequency: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate_hz, frequency, FRAC_1_SQRT_2);
+        Self::normalized(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn highpass(sample_rate_hz: f64, frequency: f64) -> Self {
+        let (cos, alpha) = Self::omega(sample_rate_hz, frequency, FRAC_1_SQRT_2);
+        Self::normalized(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// The two stages of the K-weighting filter of ITU-R BS.1770, derived for any sample rate
+    /// as in libebur128: a high shelf modelling the acoustic effect of the head, then a
+    /// high-pass filter.
+    fn k_weighting(sample_rate_hz: f64) -> [Self; 2] {
+        let k = |frequency: f64| (PI * frequency / sample_rate_hz).tan();
+
+        let (k1, q1) = (k(1_681.974_450_955_533), 0.707_175_236_955_419_6);
+        let vh = 10f64.powf(3.999_843_853_973_347 / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let shelf = Self::normalized(
+            [
+                vh + vb * k1 / q1 + k1 * k1,
+                2.0 * (k1 * k1 - vh),
+                vh - vb * k1 / q1 + k1 * k1,
+            ],
+            [
+                1.0 + k1 / q1 + k1 * k1,
+                2.0 * (k1 * k1 - 1.0),
+                1.0 - k1 / q1 + k1 * k1,
+            ],
+        );
+
+        let (k2, q2) = (k(38.135_470_876_024_44), 0.500_327_037_323_877_3);
+        let a0 = 1.0 + k2 / q2 + k2 * k2;
+        let highpass = Self {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k2 * k2 - 1.0) / a0, (1.0 - k2 / q2 + k2 * k2) / a0],
+        };
+        [shelf, highpass]
+    }
+
+    fn omega(sample_rate_hz: f64, frequency: f64, q: f64) -> (f64, f64) {
+        let frequency = frequency.min(0.49 * sample_rate_hz);
+        let w0 = 2.0 * PI * frequency / sample_rate_hz;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|b| b / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+        }
+    }
+
+    fn process(&self, audio: &mut Audio) {
+        let mut state = vec![[0.0f64; 2]; audio.channels];
+        for frame in audio.samples.chunks_exact_mut(audio.channels) {
+            for (sample, z) in frame.iter_mut().zip(&mut state) {
+                let x = *sample as f64;
+                let y = self.b[0] * x + z[0];
+                z[0] = self.b[1] * x - self.a[0] * y + z[1];
+                z[1] = self.b[2] * x - self.a[1] * y;
+                *sample = y as f32;
+            }
+        }
+    }
+}
+
+/// Measures the integrated loudness of `audio` in LUFS, as specified by ITU-R BS.1770 and used
+/// by EBU R128. Mono audio counts as if it were played on both sides, as in a voice channel.
+/// Returns `None` for silence.
+pub fn integrated_loudness(audio: &Audio) -> Option<f64> {
+    let frames = audio.frames();
+    if frames == 0 {
+        return None;
+    }
+    let rate = audio.sample_rate_hz as f64;
+
+    let mut weighted = audio.clone();
+    for stage in Biquad::k_weighting(rate) {
+        stage.process(&mut weighted);
+    }
+
+    let channel_weight = if audio.channels == 1 { 2.0 } else { 1.0 };
+    let power = |start: usize, len: usize| {
+        let samples = &weighted.samples[start * audio.channels..(start + len) * audio.channels];
+        let sum: f64 = samples.iter().map(|&s| (s as f64).powi(2)).sum();
+        channel_weight * sum / len as f64
+    };
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean = |powers: &[f64]| powers.iter().sum::<f64>() / powers.len() as f64;
+
+    // Blocks overlap by 75%. A sound shorter than a block is measured as a whole.
+    let block = ((LOUDNESS_BLOCK.as_secs_f64() * rate).round() as usize).min(frames);
+    let powers: Vec<_> = (0..=frames - block)
+        .step_by((block / 4).max(1))
+        .map(|start| power(start, block))
+        .collect();
+
+    let audible: Vec<_> = powers
+        .into_iter()
+        .filter(|&p| loudness(p) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    if audible.is_empty() {
+        return None;
+    }
+    let relative_gate = loudness(mean(&audible)) + RELATIVE_GATE_LU;
+    let gated: Vec<_> = audible
+        .into_iter()
+        .filter(|&p| loudness(p) > relative_gate)
+        .collect();
+    Some(loudness(mean(&gated)))
+}
+
+/// Peak limiter with a soft knee. The gain follows the peak level of the audio rather than
+/// single samples, so loud passages are turned down instead of being clipped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limiter {
+    /// Level no sample reaches.
+    threshold: f32,
+    /// How abruptly levels bend towards the threshold. The knee is about `1 / sharpness` wide.
+    sharpness: f32,
+}
+
+impl Limiter {
+    pub fn new(threshold: f32, sharpness: f32) -> Self {
+        Self {
+            threshold,
+            sharpness,
+        }
+    }
+
+    /// Level that a peak at `level` is brought down to. Quiet levels are left almost untouched
+    /// and loud ones approach the threshold.
+    fn curve(&self, level: f32) -> f32 {
+        let exponent = (self.sharpness * self.threshold).max(f32::EPSILON);
+        let ratio = level / self.threshold;
+        // Both forms are equal; each one keeps the power from overflowing on its side.
+        if ratio <= 1.0 {
+            level / (1.0 + ratio.powf(exponent)).powf(1.0 / exponent)
+        } else {
+            self.threshold / (1.0 + ratio.powf(-exponent)).powf(1.0 / exponent)
+        }
+    }
+
+    pub fn apply(&self, audio: &mut Audio) {
+        let release = (-1.0 / (LIMITER_RELEASE.as_secs_f32() * audio.sample_rate_hz as f32)).exp();
+        let mut envelope = 0.0f32;
+        for frame in audio.samples.chunks_exact_mut(audio.channels) {
+            // The gain reacts to a peak at once, so every sample stays below the threshold.
+            let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            envelope = peak.max(envelope * release);
+            if envelope > 0.0 {
+                let gain = self.curve(envelope) / envelope;
+                frame.iter_mut().for_each(|sample| *sample *= gain);
+            }
+        }
+    }
+}
+
+/// Applies every filter of `af` in order.
+pub fn apply_audio_filter(audio: &mut Audio, af: &AudioFilter) {
+    for filter in af.filters() {
+        apply_filter(audio, filter);
+    }
+}
+
+/// Applies a single filter. Missing parameters take the same defaults as in ffmpeg, whose
+/// syntax `af=` follows.
+fn apply_filter(audio: &mut Audio, filter: &Filter) {
+    let rate = audio.sample_rate_hz as f64;
+    match filter.name {
+        "aecho" => {
+            let in_gain = filter.get("in_gain").unwrap_or(0.6) as f32;
+            let out_gain = filter.get("out_gain").unwrap_or(0.3) as f32;
+            let delays = filter.get_list("delays").unwrap_or_else(|| vec![1000.0]);
+            let decays = filter.get_list("decays").unwrap_or_else(|| vec![0.5]);
+            let echoes: Vec<_> = delays
+                .iter()
+                .zip(&decays)
+                .map(|(delay, decay)| ((delay / 1000.0 * rate).round() as usize, *decay as f32))
+                .collect();
+            let tail = echoes.iter().map(|(delay, _)| *delay).max().unwrap_or(0);
+
+            let channels = audio.channels;
+            let frames = audio.frames();
+            let input = &audio.samples;
+            let mut samples = Vec::with_capacity((frames + tail) * channels);
+            for i in 0..frames + tail {
+                for ch in 0..channels {
+                    let at = |frame: usize| input.get(frame * channels + ch).copied();
+                    let mut sample = at(i).unwrap_or(0.0) * in_gain;
+                    for &(delay, decay) in &echoes {
+                        if let Some(echo) = i.checked_sub(delay).and_then(at) {
+                            sample += echo * decay;
+                        }
+                    }
+                    samples.push(sample * out_gain);
+                }
+            }
+            audio.samples = samples;
+        }
+        "atempo" => *audio = time_stretch(audio, filter.get("tempo").unwrap_or(1.0)),
+        "highpass" => {
+            Biquad::highpass(rate, filter.get("frequency").unwrap_or(3000.0)).process(audio)
+        }
+        "lowpass" => Biquad::lowpass(rate, filter.get("frequency").unwrap_or(500.0)).process(audio),
+        "tremolo" => {
+            let frequency = filter.get("f").unwrap_or(5.0);
+            let depth = filter.get("d").unwrap_or(0.5);
+            let channels = audio.channels;
+            for (i, frame) in audio.samples.chunks_exact_mut(channels).enumerate() {
+                let phase = 2.0 * PI * frequency * i as f64 / rate;
+                let gain = (1.0 - depth / 2.0 * (1.0 + phase.sin())) as f32;
+                frame.iter_mut().for_each(|s| *s *= gain);
+            }
+        }
+        "volume" => audio.gain(filter.get("volume").unwrap_or(1.0) as f32),
+        // Filters are validated against the allowlist when the command is parsed.
+        name => unreachable!("filter `{name}` is not implemented"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, path::PathBuf, str::FromStr};
+
+    use super::*;
+
+    fn sine(sample_rate_hz: u32, hz: f64, duration: Duration) -> Audio {
+        let frames = (duration.as_secs_f64() * sample_rate_hz as f64) as usize;
+        let samples = (0..frames)
+            .map(|i| (2.0 * PI * hz * i as f64 / sample_rate_hz as f64).sin() as f32 * 0.5)
+            .collect();
+        Audio::new(sample_rate_hz, 1, samples)
+    }
+
+    /// Counts upward zero crossings per second, which is the frequency of a pure tone.
+    fn frequency(audio: &Audio) -> f64 {
+        let crossings = audio
+            .samples
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        crossings as f64 / audio.duration().as_secs_f64()
+    }
+
+    #[test]
+    fn test_decode_file() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound/sainou.mp3");
+        let audio = decode_file(&path).unwrap();
+        assert!(audio.sample_rate_hz > 0);
+        assert!(audio.frames() > 0);
+        assert!(audio.samples.iter().all(|s| s.is_finite()));
+
+        let wav = audio.to_wav();
+        let mut hint = Hint::new();
+        hint.with_extension("wav");
+        assert_eq!(decode(Box::new(Cursor::new(wav)), &hint).unwrap(), audio);
+
+        let pcm16 = decode(Box::new(Cursor::new(audio.to_pcm16_wav())), &hint).unwrap();
+        assert_eq!(pcm16.frames(), audio.frames());
+        assert!(
+            pcm16
+                .samples
+                .iter()
+                .zip(&audio.samples)
+                .all(|(a, b)| (a - b.clamp(-1.0, 1.0)).abs() < 1e-4)
+        );
+    }
+
+    #[test]
+    fn test_resample() {
+        let audio = sine(44100, 441.0, Duration::from_secs(1));
+        let resampled = resample(&audio, 44100.0, 48000);
+        assert_eq!(resampled.sample_rate_hz, 48000);
+        assert_eq!(resampled.frames(), 48000);
+        assert!((frequency(&resampled) - 441.0).abs() < 2.0);
+
+        // Taking the samples to be twice as far apart halves the pitch and doubles the length.
+        let slowed = resample(&audio, 22050.0, 48000);
+        assert_eq!(slowed.frames(), 96000);
+        assert!((frequency(&slowed) - 220.5).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_time_stretch() {
+        let audio = sine(48000, 440.0, Duration::from_secs(1));
+        for tempo in [0.5, 0.8, 1.5, 2.0] {
+            let stretched = time_stretch(&audio, tempo);
+            assert_eq!(stretched.frames(), (48000.0 / tempo).round() as usize);
+            // The pitch is kept.
+            assert!(
+                (frequency(&stretched) - 440.0).abs() < 10.0,
+                "{tempo}: {}",
+                frequency(&stretched)
+            );
+        }
+    }
+
+    #[test]
+    fn test_audio_filters() {
+        let audio = sine(48000, 440.0, Duration::from_secs(1));
+        let peak = |audio: &Audio| audio.samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+
+        let mut echoed = audio.clone();
+        apply_audio_filter(
+            &mut echoed,
+            &AudioFilter::from_str("aecho=1:1:500:0.5").unwrap(),
+        );
+        assert_eq!(echoed.frames(), 48000 + 24000);
+
+        let mut quiet = audio.clone();
+        apply_audio_filter(
+            &mut quiet,
+            &AudioFilter::from_str("highpass=f=5000,volume=2").unwrap(),
+        );
+        assert!(peak(&quiet) < 0.1);
+
+        let mut tempo = audio.clone();
+        apply_audio_filter(&mut tempo, &AudioFilter::from_str("atempo=2").unwrap());
+        assert_eq!(tempo.frames(), 24000);
+    }
+
+    #[test]
+    fn test_integrated_loudness() {
+        // A 1 kHz tone at -20 dBFS on both sides measures -20 LUFS, whatever the sample rate.
+        for sample_rate_hz in [44100, 48000] {
+            let mono = sine(sample_rate_hz, 1000.0, Duration::from_secs(2));
+            let mut quiet = mono.clone();
+            quiet.gain(0.2);
+            let loudness = integrated_loudness(&quiet).unwrap();
+            assert!((loudness + 20.0).abs() < 0.05, "{loudness}");
+
+            let mut stereo = quiet.clone();
+            stereo.pan(0.0);
+            let loudness = integrated_loudness(&stereo).unwrap();
+            assert!((loudness + 20.0).abs() < 0.05, "{loudness}");
+
+            // Silence before the tone is gated out, except in the blocks that reach into it.
+            // Averaging it in would give -21.8 LUFS.
+            let mut padded = Audio::new(sample_rate_hz, 1, vec![0.0; sample_rate_hz as usize]);
+            padded.samples.extend(&quiet.samples);
+            let loudness = integrated_loudness(&padded).unwrap();
+            assert!((loudness + 20.0).abs() < 0.5, "{loudness}");
+        }
+
+        let short = sine(48000, 1000.0, Duration::from_millis(100));
+        assert!(integrated_loudness(&short).is_some());
+        assert_eq!(
+            integrated_loudness(&Audio::new(48000, 1, vec![0.0; 48000])),
+            None
+        );
+        assert_eq!(integrated_loudness(&Audio::new(48000, 2, vec![])), None);
+    }
+
+    #[test]
+    fn test_limiter() {
+        let peak = |audio: &Audio| audio.samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        let limiter = Limiter::new(0.2, 12.5);
+
+        // Loud tones are held below the threshold but stay close to it.
+        for gain in [1.0, 2.0, 10.0] {
+            let mut audio = sine(48000, 440.0, Duration::from_secs(1));
+            audio.gain(gain);
+            limiter.apply(&mut audio);
+            let peak = peak(&audio);
+            assert!(peak < 0.2 && peak > 0.19, "{gain}: {peak}");
+        }
+
+        // Quiet ones are barely changed.
+        let mut quiet = sine(48000, 440.0, Duration::from_secs(1));
+        quiet.gain(0.05);
+        let mut limited = quiet.clone();
+        limiter.apply(&mut limited);
+        assert!(peak(&limited) / peak(&quiet) > 0.99);
+
+        // The gain recovers after a loud burst.
+        let mut burst = Audio::new(48000, 1, vec![1.0; 4800]);
+        burst.samples.extend(&quiet.samples);
+        limiter.apply(&mut burst);
+        assert!(peak(&Audio::new(48000, 1, burst.samples[48000..].to_vec())) > 0.0245);
+
+        // A hard knee is a clipper.
+        assert!((Limiter::new(0.2, 1e6).curve(0.19) - 0.19).abs() < 1e-4);
+        assert_eq!(Limiter::new(0.2, 1e6).curve(100.0), 0.2);
+        assert_eq!(limiter.curve(0.0), 0.0);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut audio = Audio::new(1000, 1, vec![1.0; 1000]);
+        audio.trim(Duration::from_millis(100), Some(Duration::from_millis(500)));
+        assert_eq!(audio.frames(), 500);
+        audio.trim(Duration::from_secs(1), None);
+        assert_eq!(audio.frames(), 0);
+
+        let mut audio = Audio::new(1000, 1, vec![1.0; 1000]);
+        audio.fade_in(Duration::from_millis(100));
+        audio.fade_out(Duration::from_millis(100));
+        assert_eq!(audio.samples[0], 0.0);
+        assert_eq!(audio.samples[999], 0.0);
+        assert_eq!(audio.samples[500], 1.0);
+
+        audio.pan(0.5);
+        assert_eq!(audio.channels, 2);
+        assert_eq!(audio.samples[1000..1002], [0.5, 1.0]);
+
+        let mut audio = Audio::new(1000, 2, vec![1.0, 2.0, 3.0, 4.0]);
+        audio.reverse();
+        assert_eq!(audio.samples, [3.0, 4.0, 1.0, 2.0]);
+    }
+}
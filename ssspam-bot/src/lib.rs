@@ -1,10 +1,15 @@
+pub mod audio_filter;
 pub mod command;
 pub mod config;
 pub mod core;
+pub mod dsp;
+pub mod macros;
+pub mod midi;
 pub mod play;
 pub mod scripting;
 pub mod sound;
 pub mod sslang;
+pub mod synth;
 pub mod web;
 
 #[macro_use]
@@ -16,7 +21,11 @@ pub use crate::{
     command::leave_voice_channel,
     config::Configs,
     core::{ChannelManager, GuildBroadcast, OpsMessage, process_message},
-    play::{SaySoundCache, play_say_commands},
+    macros::MacroStorage,
+    play::{
+        SaySoundCache, play_say_commands, render_say_commands, render_say_commands_in_context,
+        render_say_commands_with_configs,
+    },
     scripting::interpret_rhai,
     sound::{SoundFile, SoundStorage},
     sslang::{SayCommand, SayCommandBuilder, SayCommands},
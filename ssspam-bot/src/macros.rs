@@ -0,0 +1,297 @@
+//! Server-wide macros: named say commands that can be used wherever a sound name is accepted.
+
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use serde::{Deserialize, Serialize};
+use serenity::{model::prelude::UserId, prelude::TypeMapKey};
+use tracing::warn;
+
+use crate::{
+    SayCommands, SoundStorage,
+    sound::lookup_key,
+    sslang::{Diagnostic, leading_sound_name},
+    synth::Synth,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Macro {
+    pub name: String,
+    /// Say commands the macro expands to.
+    pub body: String,
+    pub author: UserId,
+    pub updated_at: SystemTime,
+}
+
+impl From<&Macro> for ssspam_proto::ss::SayMacro {
+    fn from(r#macro: &Macro) -> Self {
+        Self {
+            name: r#macro.name.clone(),
+            body: r#macro.body.clone(),
+            updated: Some(r#macro.updated_at.into()),
+        }
+    }
+}
+
+pub struct MacroStorage {
+    /// [`lookup_key`] of the name to [`Macro`].
+    db: PickleDb,
+}
+
+impl MacroStorage {
+    pub fn load_or_create<P: AsRef<Path>>(macro_file: P) -> anyhow::Result<Self> {
+        let db = if macro_file.as_ref().exists() {
+            PickleDb::load(
+                macro_file,
+                PickleDbDumpPolicy::AutoDump,
+                SerializationMethod::Json,
+            )?
+        } else {
+            PickleDb::new(
+                macro_file,
+                PickleDbDumpPolicy::AutoDump,
+                SerializationMethod::Json,
+            )
+        };
+        let mut storage = Self { db };
+        storage.rekey()?;
+        Ok(storage)
+    }
+
+    /// Moves macros stored under keys from before names were looked up by [`lookup_key`]. Of
+    /// macros whose names now share a key, the most recently updated one is kept.
+    fn rekey(&mut self) -> anyhow::Result<()> {
+        for key in self.db.get_all() {
+            let Some(r#macro) = self.db.get::<Macro>(&key) else {
+                continue;
+            };
+            let new_key = lookup_key(&r#macro.name);
+            if new_key == key {
+                continue;
+            }
+            self.db.rem(&key).context("Failed to remove macro")?;
+            if let Some(other) = self.db.get::<Macro>(&new_key) {
+                warn!(
+                    "Macros `{}` and `{}` have the same normalized name; keeping the newer one",
+                    other.name, r#macro.name
+                );
+                if other.updated_at >= r#macro.updated_at {
+                    continue;
+                }
+            }
+            self.db
+                .set(&new_key, &r#macro)
+                .context("Failed to set macro")?;
+        }
+        Ok(())
+    }
+
+    pub fn macros(&self) -> Vec<Macro> {
+        let mut macros: Vec<_> = self
+            .db
+            .get_all()
+            .iter()
+            .filter_map(|key| self.db.get::<Macro>(key))
+            .collect();
+        macros.sort_by(|a, b| a.name.cmp(&b.name));
+        macros
+    }
+
+    pub fn get(&self, name: impl AsRef<str>) -> Option<Macro> {
+        self.db.get::<Macro>(&lookup_key(name.as_ref()))
+    }
+
+    /// Defines or redefines a macro.
+    ///
+    /// Fails if `name` is taken by a sound or reserved for a synthetic one, if `body` does not
+    /// parse or plays nothing, or if the macro would (indirectly) expand to itself.
+    pub fn set(
+        &mut self,
+        name: &str,
+        body: &str,
+        author: UserId,
+        storage: &SoundStorage,
+    ) -> anyhow::Result<()> {
+        if leading_sound_name(name) != Some(name) {
+            bail!("`{name}` is not a valid sound name");
+        }
+        if storage.get(name).is_some() {
+            bail!("A sound named `{name}` already exists");
+        }
+        if Synth::from_name(name).is_some() {
+            bail!("`{name}` is reserved for a synthetic sound");
+        }
+        if SayCommands::from_str(body)
+            .map_err(|e| anyhow!("{}", e.render(body)))?
+            .is_empty()
+        {
+            bail!("The body of `{name}` plays nothing");
+        }
+
+        // Expanding the new name with the new definition in place catches cycles through it.
+        let mut probe = SayCommands::from_str(name)?;
+        probe
+            .expand_macros(&|n| {
+                if lookup_key(n) == lookup_key(name) {
+                    Some(body.to_owned())
+                } else {
+                    self.lookup(n, storage)
+                }
+            })
+            .map_err(|e| anyhow!("{e}"))?;
+
+        let r#macro = Macro {
+            name: name.to_owned(),
+            body: body.to_owned(),
+            author,
+            updated_at: SystemTime::now(),
+        };
+        self.db
+            .set(&lookup_key(name), &r#macro)
+            .context("Failed to set macro")
+    }
+
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<bool> {
+        self.db
+            .rem(&lookup_key(name))
+            .context("Failed to remove macro")
+    }
+
+    /// Expands every macro in `say_commands`. Sounds, including synthetic ones, take precedence
+    /// over macros of the same name.
+    pub fn expand(
+        &self,
+        say_commands: &mut SayCommands,
+        storage: &SoundStorage,
+    ) -> Result<(), Diagnostic> {
+        say_commands.expand_macros(&|name| self.lookup(name, storage))
+    }
+
+    fn lookup(&self, name: &str, storage: &SoundStorage) -> Option<String> {
+        if storage.get(name).is_some() || Synth::from_name(name).is_some() {
+            return None;
+        }
+        self.get(name).map(|r#macro| r#macro.body)
+    }
+
+    pub fn calc_similarities(&self, query: impl AsRef<str>) -> Vec<(f64, Macro)> {
+        let query = lookup_key(query.as_ref());
+        let mut sims: Vec<_> = self
+            .macros()
+            .into_iter()
+            .map(|r#macro| {
+                (
+                    strsim::jaro_winkler(&query, &lookup_key(&r#macro.name)),
+                    r#macro,
+                )
+            })
+            .collect();
+        sims.sort_by(|(d1, _), (d2, _)| d2.partial_cmp(d1).unwrap());
+        sims
+    }
+}
+
+impl TypeMapKey for MacroStorage {
+    type Value = Arc<RwLock<Self>>;
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::sslang::DiagnosticKind;
+
+    #[test]
+    fn test_set_and_expand() {
+        let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let storage = SoundStorage::load(sound_dir);
+        let temp_dir = tempdir().unwrap();
+        let mut macros = MacroStorage::load_or_create(temp_dir.path().join("macros.json")).unwrap();
+        let author = UserId::new(1);
+
+        macros
+            .set("intro", "d 120; sainou p80", author, &storage)
+            .unwrap();
+        macros
+            .set("Outro", "intro*2 | d", author, &storage)
+            .unwrap();
+        assert!(macros.set("d", "sainou", author, &storage).is_err());
+        assert!(macros.set("sine440", "sainou", author, &storage).is_err());
+        assert!(macros.set("bad", "d pX", author, &storage).is_err());
+        assert!(macros.set("intro", "outro", author, &storage).is_err());
+
+        let mut saycmds = SayCommands::from_str("outro; d").unwrap();
+        macros.expand(&mut saycmds, &storage).unwrap();
+        assert_eq!(saycmds.to_string(), "((d 120; sainou p80)*2| d); d");
+        // Commands from a macro point at its invocation.
+        assert_eq!(
+            saycmds
+                .iter()
+                .map(|cmd| cmd.span.range())
+                .collect::<Vec<_>>(),
+            [0..5, 0..5, 0..5, 7..8]
+        );
+
+        // Macros are persisted.
+        let macros = MacroStorage::load_or_create(temp_dir.path().join("macros.json")).unwrap();
+        assert_eq!(
+            macros
+                .macros()
+                .iter()
+                .map(|m| m.name.as_str())
+                .collect::<Vec<_>>(),
+            ["Outro", "intro"]
+        );
+        assert_eq!(macros.calc_similarities("outr")[0].1.name, "Outro");
+
+        let mut saycmds = SayCommands::from_str("intro 120").unwrap();
+        let diag = macros.expand(&mut saycmds, &storage).unwrap_err();
+        assert_eq!(diag.span, 0..5);
+        assert!(matches!(diag.kind, DiagnosticKind::Macro { .. }));
+    }
+
+    #[test]
+    fn test_normalized_names() {
+        let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let storage = SoundStorage::load(sound_dir);
+        let temp_dir = tempdir().unwrap();
+        let macro_file = temp_dir.path().join("macros.json");
+        let author = UserId::new(1);
+
+        let mut macros = MacroStorage::load_or_create(&macro_file).unwrap();
+        macros.set("サイノウ", "sainou", author, &storage).unwrap();
+        assert_eq!(macros.get("ｻｲﾉｳ").unwrap().name, "サイノウ");
+        let mut saycmds = SayCommands::from_str("ｻｲﾉｳ").unwrap();
+        macros.expand(&mut saycmds, &storage).unwrap();
+        assert_eq!(saycmds.to_string(), "(sainou)");
+        // Redefining under another spelling replaces the macro.
+        macros.set("さいのう", "d", author, &storage).unwrap();
+        assert_eq!(macros.macros().len(), 1);
+        assert!(macros.set("ｻｲﾉｳ", "さいのう", author, &storage).is_err());
+
+        // Macros stored under their lowercased names are moved to their lookup keys.
+        let r#macro = Macro {
+            name: "ＯＵＴＲＯ".to_owned(),
+            body: "d".to_owned(),
+            author,
+            updated_at: SystemTime::now(),
+        };
+        macros.db.set("ｏｕｔｒｏ", &r#macro).unwrap();
+        let macros = MacroStorage::load_or_create(&macro_file).unwrap();
+        assert_eq!(macros.get("outro").unwrap().name, "ＯＵＴＲＯ");
+        assert_eq!(macros.macros().len(), 2);
+    }
+}
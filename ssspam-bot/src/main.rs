@@ -17,9 +17,11 @@ use serenity::{
 };
 use songbird::{self, SerenityInit};
 use ssspam_bot::{
-    ChannelManager, Configs, GuildBroadcast, SaySoundCache, SoundStorage, command,
-    command::play_join_or_leave_sound, core::ChannelUserManager, leave_voice_channel,
-    process_message, sound::watch_sound_storage,
+    ChannelManager, Configs, GuildBroadcast, MacroStorage, SaySoundCache, SoundStorage, command,
+    command::play_join_or_leave_sound,
+    core::{ChannelUserManager, PlaybackCoordinator},
+    leave_voice_channel, process_message,
+    sound::watch_sound_storage,
 };
 use tracing::{info, warn};
 use tracing_opentelemetry::OpenTelemetryLayer;
@@ -110,6 +112,18 @@ struct Opt {
     #[clap(long, env, value_parser)]
     config_dir: PathBuf,
 
+    /// Where rendered say sounds are kept across restarts [default: <CONFIG_DIR>/cache]
+    #[clap(long, env, value_parser)]
+    cache_dir: Option<PathBuf>,
+
+    /// Size of the decoded say sounds kept in memory
+    #[clap(long, env, default_value_t = 64 * 1024 * 1024)]
+    cache_memory_bytes: u64,
+
+    /// Size of the rendered say sounds kept in the cache directory
+    #[clap(long, env, default_value_t = 1024 * 1024 * 1024)]
+    cache_disk_bytes: u64,
+
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
 }
@@ -138,8 +152,12 @@ async fn main() -> anyhow::Result<()> {
                 command::help(),
                 command::join(),
                 command::leave(),
+                command::macro_(),
+                command::midi(),
                 command::mute(),
+                command::queue(),
                 command::r(),
+                command::render(),
                 command::restart(),
                 command::rhai(),
                 command::s(),
@@ -161,6 +179,7 @@ async fn main() -> anyhow::Result<()> {
     let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
 
     let configs = Configs::load_or_create(opt.config_dir.join("config.json"))?;
+    let macros = MacroStorage::load_or_create(opt.config_dir.join("macros.json"))?;
 
     let mut client = Client::builder(&opt.discord_token, intents)
         .event_handler(Handler)
@@ -172,7 +191,13 @@ async fn main() -> anyhow::Result<()> {
     {
         let mut data = client.data.write().await;
 
-        let storage = Arc::new(RwLock::new(SoundStorage::load(&opt.sound_dir)));
+        let cache_dir = opt
+            .cache_dir
+            .unwrap_or_else(|| opt.config_dir.join("cache"));
+
+        let storage = Arc::new(RwLock::new(
+            SoundStorage::load(&opt.sound_dir).with_measurement_dir(cache_dir.join("loudness"))?,
+        ));
         tokio::spawn(watch_sound_storage(Arc::clone(&storage)));
         data.insert::<SoundStorage>(storage);
 
@@ -182,11 +207,18 @@ async fn main() -> anyhow::Result<()> {
 
         data.insert::<ChannelUserManager>(Arc::new(ChannelUserManager::default()));
 
-        data.insert::<SaySoundCache>(Arc::new(SaySoundCache::new(50)));
+        data.insert::<SaySoundCache>(Arc::new(
+            SaySoundCache::new(opt.cache_memory_bytes)
+                .with_disk(cache_dir, opt.cache_disk_bytes)?,
+        ));
 
         data.insert::<GuildBroadcast>(Arc::new(Mutex::new(GuildBroadcast::new())));
 
+        data.insert::<PlaybackCoordinator>(Arc::new(PlaybackCoordinator::default()));
+
         data.insert::<Configs>(Arc::new(RwLock::new(configs)));
+
+        data.insert::<MacroStorage>(Arc::new(RwLock::new(macros)));
     }
 
     let shard_manager = client.shard_manager.clone();
@@ -0,0 +1,255 @@
+//! Conversion of Standard MIDI Files into say commands that play a sound as an instrument.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use anyhow::bail;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::{
+    SayCommand, SayCommands,
+    sslang::{MAX_PITCH_SEMITONES, MIN_PITCH_SEMITONES, Pitch},
+};
+
+/// Note played at the original pitch of the instrument unless told otherwise (C4).
+pub const DEFAULT_BASE_NOTE: u8 = 60;
+
+/// Tempo of a file until its first tempo event, in microseconds per beat (120 bpm).
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Channel 10, which General MIDI reserves for drums. Drums have no meaningful pitch.
+const PERCUSSION_CHANNEL: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub key: u8,
+    pub velocity: u8,
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// Reads every non-percussion note of a Standard MIDI File, ordered by start.
+pub fn read_notes(data: &[u8]) -> anyhow::Result<Vec<Note>> {
+    let smf = Smf::parse(data)?;
+
+    // Tempo changes apply to all tracks, so the events of all tracks are merged first.
+    let mut events = Vec::new();
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, event.kind));
+        }
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut clock = Clock::new(smf.header.timing);
+    let mut pressed: HashMap<(u8, u8), VecDeque<(Duration, u8)>> = HashMap::new();
+    let mut notes = Vec::new();
+    for (tick, kind) in events {
+        let now = clock.advance(tick);
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => clock.tempo = tempo.as_int(),
+            TrackEventKind::Midi { channel, message } if channel.as_int() != PERCUSSION_CHANNEL => {
+                let channel = channel.as_int();
+                match message {
+                    MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                        pressed
+                            .entry((channel, key.as_int()))
+                            .or_default()
+                            .push_back((now, vel.as_int()));
+                    }
+                    // A note-on without velocity is a note-off.
+                    MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                        let Some((start, velocity)) = pressed
+                            .get_mut(&(channel, key.as_int()))
+                            .and_then(VecDeque::pop_front)
+                        else {
+                            continue;
+                        };
+                        notes.push(Note {
+                            key: key.as_int(),
+                            velocity,
+                            start,
+                            duration: now - start,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Notes that are never released last until the end of the file.
+    let end = clock.now;
+    for ((_, key), starts) in pressed {
+        for (start, velocity) in starts {
+            notes.push(Note {
+                key,
+                velocity,
+                start,
+                duration: end - start,
+            });
+        }
+    }
+
+    notes.sort_by_key(|note| (note.start, note.key));
+    Ok(notes)
+}
+
+/// Maps `key` to the pitch that turns an instrument sounding at `base_note` into it.
+pub fn pitch_for_note(key: u8, base_note: u8) -> Pitch {
+    Pitch::Cents((key as i32 - base_note as i32) * 100)
+}
+
+/// Builds say commands that play `instrument` at every note of a Standard MIDI File. Each note
+/// starts at its absolute offset, so the result does not depend on the length of the sound.
+pub fn say_commands_from_midi(
+    data: &[u8],
+    instrument: &str,
+    base_note: u8,
+) -> anyhow::Result<SayCommands> {
+    let notes = read_notes(data)?;
+    if notes.is_empty() {
+        bail!("The file has no notes");
+    }
+    let range = MIN_PITCH_SEMITONES..=MAX_PITCH_SEMITONES;
+    let commands: Vec<_> = notes
+        .into_iter()
+        .filter(|note| range.contains(&(note.key as i32 - base_note as i32)))
+        .map(|note| SayCommand {
+            name: instrument.to_owned(),
+            pitch: pitch_for_note(note.key, base_note),
+            volume: note.velocity as u32 * 100 / 127,
+            // Notes that end where they start are left to ring out.
+            duration: (!note.duration.is_zero()).then_some(note.duration.as_millis() as u32),
+            at: Some(note.start.as_millis() as u32),
+            ..Default::default()
+        })
+        .collect();
+    if commands.is_empty() {
+        bail!("Every note is out of the playable pitch range");
+    }
+    Ok(commands.into())
+}
+
+/// Converts ticks into time, following tempo changes.
+struct Clock {
+    timing: Timing,
+    /// Microseconds per beat.
+    tempo: u32,
+    tick: u64,
+    now: Duration,
+}
+
+impl Clock {
+    const fn new(timing: Timing) -> Self {
+        Self {
+            timing,
+            tempo: DEFAULT_TEMPO,
+            tick: 0,
+            now: Duration::ZERO,
+        }
+    }
+
+    /// Moves the clock to `tick` and returns the time at it.
+    fn advance(&mut self, tick: u64) -> Duration {
+        let ticks = (tick - self.tick) as f64;
+        let micros = match self.timing {
+            Timing::Metrical(ticks_per_beat) => {
+                ticks * self.tempo as f64 / ticks_per_beat.as_int().max(1) as f64
+            }
+            Timing::Timecode(fps, ticks_per_frame) => {
+                ticks * 1_000_000.0 / (fps.as_f32() as f64 * ticks_per_frame.max(1) as f64)
+            }
+        };
+        self.tick = tick;
+        self.now += Duration::from_secs_f64(micros / 1_000_000.0);
+        self.now
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use midly::{Format, Header, TrackEvent};
+
+    use super::*;
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind,
+        }
+    }
+
+    fn note_on(channel: u8, key: u8, vel: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_say_commands_from_midi() {
+        // 480 ticks per beat at 120 bpm, so a beat lasts 500 ms until the tempo doubles.
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(480.into())));
+        smf.tracks.push(vec![
+            event(0, TrackEventKind::Meta(MetaMessage::Tempo(500_000.into()))),
+            event(
+                960,
+                TrackEventKind::Meta(MetaMessage::Tempo(250_000.into())),
+            ),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+        smf.tracks.push(vec![
+            event(0, note_on(0, 60, 127)),
+            event(480, note_on(0, 60, 0)),
+            event(0, note_on(0, 67, 64)),
+            event(0, note_on(PERCUSSION_CHANNEL, 36, 100)),
+            event(960, note_on(0, 67, 0)),
+            event(0, note_on(0, 48, 100)),
+        ]);
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+
+        let notes = read_notes(&data).unwrap();
+        assert_eq!(
+            notes,
+            [
+                Note {
+                    key: 60,
+                    velocity: 127,
+                    start: Duration::ZERO,
+                    duration: Duration::from_millis(500),
+                },
+                Note {
+                    key: 67,
+                    velocity: 64,
+                    start: Duration::from_millis(500),
+                    duration: Duration::from_millis(750),
+                },
+                Note {
+                    key: 48,
+                    velocity: 100,
+                    start: Duration::from_millis(1250),
+                    duration: Duration::ZERO,
+                },
+            ]
+        );
+
+        let saycmds = say_commands_from_midi(&data, "sainou", 55).unwrap();
+        assert_eq!(
+            saycmds.to_string(),
+            "sainou @t0 p+5st d0.5; sainou @t0.5 p+12st v50 d0.75; sainou @t1.25 p-7st v78"
+        );
+
+        assert!(say_commands_from_midi(&data, "sainou", 0).is_err());
+        assert!(say_commands_from_midi(b"not midi", "sainou", 60).is_err());
+    }
+}
@@ -1,13 +1,16 @@
 use std::{
     cmp,
-    process::{Command, Stdio},
-    sync::Arc,
-    time::Duration,
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime},
 };
 
 use anyhow::Context as _;
-use quick_cache::sync::Cache;
+use quick_cache::{Weighter, sync::Cache};
 use serenity::{client::Context, model::id::GuildId, prelude::TypeMapKey};
+use sha1::{Digest, Sha1};
 use songbird::{
     Call,
     input::cached::Memory,
@@ -16,32 +19,247 @@ use songbird::{
 use tokio::sync::Mutex;
 use tracing::warn;
 
-use crate::{SayCommand, SayCommands, SoundFile, SoundStorage, sslang::Action};
+use crate::{
+    Configs, SayCommand, SayCommands, SoundFile, SoundStorage,
+    config::{DEFAULT_CLIP_THRESHOLD, DEFAULT_LOUDNESS_TARGET, DEFAULT_SHARPNESS},
+    dsp::{self, Audio, Limiter},
+    sound::lookup_key,
+    sslang::{Action, SayNode},
+    synth::{self, Synth},
+};
 
 static MAX_PLAYABLE_DURATION: Duration = Duration::from_secs(180);
 static VOLUME: f32 = 0.05;
 
+/// Size of a few seconds of decoded stereo audio, used to estimate how many sounds fit in the
+/// in-memory cache.
+const TYPICAL_DECODED_BYTES: u64 = 1024 * 1024;
+
+/// Largest boost given to quiet sounds, so that ones that are mostly noise are not blown up.
+const MAX_LOUDNESS_BOOST_DB: f64 = 12.0;
+
+/// Part of every disk cache key. Bump it when rendering changes so that old renders are not
+/// served.
+const DISK_CACHE_VERSION: u32 = 1;
+
+/// Sample rate songbird mixes at, so tracks need no further resampling.
+const OUTPUT_SAMPLE_RATE_HZ: u32 = 48000;
+
+/// Decoded say sounds in two tiers: in memory, bounded by the size of the decoded data, and
+/// optionally on disk, where rendered sounds survive restarts.
 pub struct SaySoundCache {
-    cache: Cache<SayCommand, Arc<DecodedSaySound>>,
+    memory: Cache<SayCommand, Arc<DecodedSaySound>, SizeWeighter>,
+    disk: Option<DiskCache>,
 }
 
 impl SaySoundCache {
-    pub fn new(max_capacity: usize) -> Self {
+    /// Keeps up to `memory_bytes` of decoded data in memory.
+    pub fn new(memory_bytes: u64) -> Self {
+        let estimated_items = (memory_bytes / TYPICAL_DECODED_BYTES).max(1) as usize;
         Self {
-            cache: Cache::new(max_capacity),
+            memory: Cache::with_weighter(estimated_items, memory_bytes, SizeWeighter),
+            disk: None,
         }
     }
 
+    /// Also keeps up to `max_bytes` of rendered sounds in `dir`.
+    pub fn with_disk(mut self, dir: impl Into<PathBuf>, max_bytes: u64) -> anyhow::Result<Self> {
+        self.disk = Some(DiskCache::open(dir.into(), max_bytes)?);
+        Ok(self)
+    }
+
     fn get(&self, say_command: &SayCommand) -> Option<Arc<DecodedSaySound>> {
-        self.cache.get(say_command)
+        self.memory.get(&Self::key(say_command))
+    }
+
+    fn insert(&self, say_command: &SayCommand, say_sound: Arc<DecodedSaySound>) {
+        self.memory.insert(Self::key(say_command), say_sound);
+    }
+
+    /// Volume and start offset are applied when the sound is mixed rather than baked into the
+    /// decoded data, so commands that only differ in them share an entry. So do names that
+    /// look up the same sound.
+    fn key(say_command: &SayCommand) -> SayCommand {
+        SayCommand {
+            name: lookup_key(&say_command.name),
+            volume: 100,
+            at: None,
+            ..say_command.clone()
+        }
     }
 
-    fn insert(&self, say_command: SayCommand, say_sound: Arc<DecodedSaySound>) {
-        self.cache.insert(say_command, say_sound);
+    /// Reads the render of `command` from disk, or renders and stores it. This blocks.
+    fn load_or_render(&self, command: &SayCommand, source: &Source) -> anyhow::Result<Audio> {
+        let Some(ref disk) = self.disk else {
+            return render(command, source);
+        };
+        let key = Self::disk_key(command, source);
+        if let Some(audio) = disk.get(&key) {
+            return Ok(audio);
+        }
+        let audio = render(command, source)?;
+        if let Err(e) = disk.insert(&key, &audio.to_wav()) {
+            warn!("Error writing to the disk cache: {e:?}");
+        }
+        Ok(audio)
+    }
+
+    /// Hashes everything the rendered audio depends on. Files are identified by their content,
+    /// so a replaced sound is never served from an old render.
+    fn disk_key(command: &SayCommand, source: &Source) -> String {
+        let source = match source {
+            Source::File(file) => file.content_hash(),
+            Source::Synth(_) => "synth",
+        };
+        let key = format!("{DISK_CACHE_VERSION}\n{}\n{source}", Self::key(command));
+        format!("{:x}", Sha1::digest(key))
+    }
+
+    /// Drops the sounds called `names` from memory, e.g. after they were uploaded or deleted.
+    /// Their renders on disk are keyed by the old content, so they are no longer looked up and
+    /// are eventually evicted.
+    pub fn invalidate(&self, names: &[String]) {
+        let keys: HashSet<_> = names.iter().map(|name| lookup_key(name)).collect();
+        self.memory.retain(|key, _| !keys.contains(&key.name));
     }
 
     pub fn clean(&self) {
-        self.cache.clear()
+        self.memory.clear();
+        if let Some(ref disk) = self.disk
+            && let Err(e) = disk.clear()
+        {
+            warn!("Error clearing the disk cache: {e:?}");
+        }
+    }
+}
+
+/// Weighs cached sounds by the size of their decoded data.
+#[derive(Clone)]
+struct SizeWeighter;
+
+impl Weighter<SayCommand, Arc<DecodedSaySound>> for SizeWeighter {
+    fn weight(&self, _key: &SayCommand, val: &Arc<DecodedSaySound>) -> u64 {
+        (val.decoded_data.samples.len() * size_of::<f32>()).max(1) as u64
+    }
+}
+
+/// Rendered say sounds stored as wav files named after a hash of what they were rendered from.
+struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Size of the renders in `dir`, so that it need not be listed on every insert.
+    total_bytes: StdMutex<u64>,
+}
+
+impl DiskCache {
+    /// Opens the cache in `dir`, removing temporary files left behind by a crash mid-write.
+    fn open(dir: PathBuf, max_bytes: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("Could not create {dir:?}"))?;
+        let cache = Self {
+            dir,
+            max_bytes,
+            total_bytes: StdMutex::new(0),
+        };
+        for path in cache.temporary_files()? {
+            fs::remove_file(path).ok();
+        }
+        *cache.total_bytes.lock().unwrap() = cache.size()?;
+        Ok(cache)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.wav"))
+    }
+
+    fn get(&self, key: &str) -> Option<Audio> {
+        let path = self.path(key);
+        if !path.exists() {
+            return None;
+        }
+        let audio = dsp::decode_file(&path)
+            .inspect_err(|e| warn!("Error reading {path:?} from the disk cache: {e:?}"))
+            .ok()?;
+        // The modification time doubles as the last access time for eviction.
+        if let Ok(file) = fs::File::options().append(true).open(&path) {
+            file.set_modified(SystemTime::now()).ok();
+        }
+        Some(audio)
+    }
+
+    fn insert(&self, key: &str, wav: &[u8]) -> anyhow::Result<()> {
+        // Writing to a temporary file first keeps concurrent readers from seeing a partial file.
+        let tmp = self
+            .dir
+            .join(format!("{key}.{:08x}.tmp", rand::random::<u32>()));
+        let path = self.path(key);
+        let replaced = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        if let Err(e) = fs::write(&tmp, wav).and_then(|()| fs::rename(&tmp, &path)) {
+            fs::remove_file(&tmp).ok();
+            return Err(e.into());
+        }
+
+        let mut total = self.total_bytes.lock().unwrap();
+        *total = (*total + wav.len() as u64).saturating_sub(replaced);
+        if *total > self.max_bytes {
+            *total = self.evict()?;
+        }
+        Ok(())
+    }
+
+    fn temporary_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn entries(&self) -> anyhow::Result<Vec<(SystemTime, u64, PathBuf)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "wav") {
+                let metadata = fs::metadata(&path)?;
+                entries.push((metadata.modified()?, metadata.len(), path));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn size(&self) -> anyhow::Result<u64> {
+        Ok(self.entries()?.iter().map(|(_, len, _)| len).sum())
+    }
+
+    /// Removes the least recently used renders until the rest fit in the budget, and returns the
+    /// size of the rest.
+    fn evict(&self) -> anyhow::Result<u64> {
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+        Ok(total)
+    }
+
+    fn clear(&self) -> anyhow::Result<()> {
+        let mut total = self.total_bytes.lock().unwrap();
+        for (_, _, path) in self.entries()? {
+            fs::remove_file(path).ok();
+        }
+        for path in self.temporary_files()? {
+            fs::remove_file(path).ok();
+        }
+        *total = self.size()?;
+        Ok(())
     }
 }
 
@@ -49,27 +267,105 @@ impl TypeMapKey for SaySoundCache {
     type Value = Arc<Self>;
 }
 
+/// A decoded sound and its timing.
 #[derive(Clone)]
 struct DecodedSaySound {
-    decoded_data: Memory,
+    decoded_data: Audio,
 
     /// Duration to block until next say sound is played.
     blocking_duration: Duration,
 
     /// Duration of this say sound.
     playing_duration: Duration,
+
+    /// Integrated loudness of the source, or `None` if it is synthetic or silent.
+    loudness_lufs: Option<f64>,
+}
+
+/// A decoded sound together with the settings that are applied when it is mixed.
+#[derive(Clone)]
+struct PreparedSaySound {
+    decoded: Arc<DecodedSaySound>,
+    volume: f32,
+}
+
+struct ScheduledSaySound {
+    /// When the sound starts, relative to the start of the message.
+    offset: Duration,
+    sound: PreparedSaySound,
+}
+
+/// What a command plays.
+#[derive(Debug, Clone)]
+enum Source {
+    File(SoundFile),
+    Synth(Synth),
+}
+
+impl Source {
+    /// Looks up `name`. Synthetic sounds take precedence since their names are reserved.
+    fn get(storage: &SoundStorage, name: &str) -> Option<Self> {
+        Synth::from_name(name)
+            .map(Self::Synth)
+            .or_else(|| storage.get(name).map(Self::File))
+    }
+
+    /// Length of the source when played by `command`. A synthetic sound lasts as long as the
+    /// command asks for.
+    fn duration(&self, command: &SayCommand) -> Duration {
+        match self {
+            Self::File(file) => file.duration(),
+            Self::Synth(_) => {
+                command.start_offset(Duration::ZERO)
+                    + command.duration.map_or(synth::DEFAULT_DURATION, |dur| {
+                        Duration::from_millis(dur as u64)
+                    })
+            }
+        }
+    }
+
+    fn loudness_lufs(&self) -> Option<f64> {
+        match self {
+            Self::File(file) => file.loudness_lufs(),
+            Self::Synth(_) => None,
+        }
+    }
+
+    /// Reads the whole source. A synthetic sound is generated as long as the command plays it.
+    fn load(&self, command: &SayCommand) -> anyhow::Result<Audio> {
+        match self {
+            Self::File(file) => dsp::decode_file(&file.path),
+            Self::Synth(synth) => Ok(synth.generate(self.duration(command))),
+        }
+    }
 }
 
 impl DecodedSaySound {
-    #[tracing::instrument]
-    async fn from_command_and_file(command: &SayCommand, file: &SoundFile) -> anyhow::Result<Self> {
-        let decoded_data = decode(command, file).await?;
+    #[tracing::instrument(skip(cache))]
+    async fn from_command_and_source(
+        command: &SayCommand,
+        source: &Source,
+        cache: Arc<SaySoundCache>,
+    ) -> anyhow::Result<Self> {
+        let (decoded_data, source) = {
+            let (command, source) = (command.clone(), source.clone());
+            tokio::task::spawn_blocking(move || {
+                let audio = cache.load_or_render(&command, &source)?;
+                // Loads the metadata and loudness used below while still off the async runtime.
+                source.duration(&command);
+                source.loudness_lufs();
+                anyhow::Ok((audio, source))
+            })
+            .await??
+        };
+        Ok(Self::new(command, &source, decoded_data))
+    }
 
+    fn new(command: &SayCommand, source: &Source, decoded_data: Audio) -> Self {
         let playing_duration = {
-            let mut dur = cmp::max(
-                (file.duration().as_millis() as i64) - command.start as i64,
-                0,
-            );
+            let source_duration = source.duration(command);
+            let start = command.start_offset(source_duration);
+            let mut dur = source_duration.saturating_sub(start).as_millis() as i64;
             if let Some(n) = command.duration {
                 dur = cmp::min(dur, n as i64)
             }
@@ -89,71 +385,75 @@ impl DecodedSaySound {
             Action::Concat => playing_duration,
         };
 
-        Ok(Self {
+        Self {
             decoded_data,
             blocking_duration,
             playing_duration,
-        })
+            loudness_lufs: source.loudness_lufs(),
+        }
     }
 }
 
-#[tracing::instrument]
-async fn decode(command: &SayCommand, file: &SoundFile) -> anyhow::Result<Memory> {
-    let audio_filters = {
-        let speed_multiplier = command.speed as f64 / 100.0;
-        let pitch_multiplier = command.pitch as f64 / 100.0;
-        let asetrate = file.sample_rate_hz() as f64 * speed_multiplier * pitch_multiplier;
-        let atempo = 1.0 / pitch_multiplier;
-        let mut afs = vec![
-            format!("asetrate={asetrate}"),
-            format!("atempo={atempo}"),
-            format!("aresample={}", file.sample_rate_hz()),
-        ];
-        if let Some(af) = command.audio_filter.clone() {
-            afs.push(af);
-        }
-        afs
-    };
+/// Renders `source` as specified by `command`.
+fn render(command: &SayCommand, source: &Source) -> anyhow::Result<Audio> {
+    let mut audio = source.load(command)?;
 
-    let t_opt_value = match command.duration {
-        Some(dur) => format!("{dur}ms"),
-        None if command.stop => format!("{}ms", command.wait),
-        None => "0".to_string(),
+    let length = match command.duration {
+        Some(dur) => Some(Duration::from_millis(dur as u64)),
+        None if command.stop => Some(Duration::from_millis(command.wait as u64)),
+        None => None,
     };
+    audio.trim(command.start_offset(audio.duration()), length);
+
+    // Only what can be played at the final speed is processed, so that no step allocates much
+    // more than is played. Reversed sounds play their end first.
+    let speed_multiplier = command.speed as f64 / 100.0;
+    let playable = MAX_PLAYABLE_DURATION.mul_f64(speed_multiplier);
+    if command.reverse {
+        audio.trim(audio.duration().saturating_sub(playable), None);
+    } else {
+        audio.truncate(playable);
+    }
+
+    // Playing the samples faster raises speed and pitch together; stretching the result back
+    // leaves only the change in speed. Whichever step shortens the audio goes first, so that the
+    // intermediate result is no longer than both the input and the output.
+    let pitch_multiplier = command.pitch.ratio();
+    let rate = audio.sample_rate_hz as f64 * speed_multiplier * pitch_multiplier;
+    if pitch_multiplier < 1.0 {
+        audio = dsp::time_stretch(&audio, 1.0 / pitch_multiplier);
+        audio = dsp::resample(&audio, rate, OUTPUT_SAMPLE_RATE_HZ);
+    } else {
+        audio = dsp::resample(&audio, rate, OUTPUT_SAMPLE_RATE_HZ);
+        audio = dsp::time_stretch(&audio, 1.0 / pitch_multiplier);
+    }
 
-    let ffmpeg_out = Command::new("ffmpeg")
-        .args([
-            "-ss",
-            &format!("{}ms", command.start),
-            "-t",
-            &t_opt_value,
-            "-i",
-            (file.path.to_str().unwrap()),
-            "-f",
-            "wav",
-            "-ac",
-            &file.channel_count().to_string(),
-            "-ar",
-            "48000",
-            "-acodec",
-            "pcm_f32le",
-            "-t",
-            "180",
-            "-af",
-            &audio_filters.join(","),
-            "-",
-        ])
-        .stderr(Stdio::null())
-        .stdin(Stdio::null())
-        .output()?;
-    Ok(Memory::new(ffmpeg_out.stdout.into()).await?)
+    if command.reverse {
+        audio.reverse();
+    }
+    if command.fade_in != 0 {
+        audio.fade_in(Duration::from_millis(command.fade_in as u64));
+    }
+    if command.fade_out != 0 {
+        audio.fade_out(Duration::from_millis(command.fade_out as u64));
+    }
+    if let Some(ref af) = command.audio_filter {
+        dsp::apply_audio_filter(&mut audio, af);
+    }
+    if command.pan != 0 {
+        audio.pan(command.pan as f64 / 100.0);
+    }
+    audio.truncate(MAX_PLAYABLE_DURATION);
+    Ok(audio)
 }
 
+/// Decodes every command and lays the results out on a timeline relative to the start of the
+/// message.
 #[tracing::instrument]
 async fn process_say_commands(
-    say_commands: SayCommands,
+    mut say_commands: SayCommands,
     ctx: &Context,
-) -> anyhow::Result<Vec<Arc<DecodedSaySound>>> {
+) -> anyhow::Result<Vec<ScheduledSaySound>> {
     let cache = ctx
         .data
         .read()
@@ -168,41 +468,363 @@ async fn process_say_commands(
         .get::<SoundStorage>()
         .context("Could not get SoundStorage")?
         .clone();
+    let configs = ctx
+        .data
+        .read()
+        .await
+        .get::<Configs>()
+        .context("Could not get Configs")?
+        .clone();
+    let loudness_target = configs.read().unwrap().get_loudness_target();
 
-    let mut decoded_sounds = Vec::new();
-    for say_command in say_commands.into_iter() {
-        let decoded = cache.get(&say_command);
+    {
+        let storage = storage.read().unwrap();
+        say_commands.resolve_choices(&storage, None);
+        say_commands.resolve_markers(&storage);
+    }
+
+    // One entry per command in source order; `None` if the sound is unknown or failed to decode.
+    let mut prepared_sounds = Vec::new();
+    for say_command in say_commands.iter() {
+        let volume = |decoded: &DecodedSaySound| {
+            say_command.volume as f32 / 100.0
+                * loudness_gain(decoded.loudness_lufs, loudness_target)
+        };
+        let decoded = cache.get(say_command);
         if let Some(decoded) = decoded {
-            decoded_sounds.push(decoded);
+            let volume = volume(&decoded);
+            prepared_sounds.push(Some(PreparedSaySound { decoded, volume }));
             continue;
         }
 
-        let sound_file = { storage.read().unwrap().get(&say_command.name) };
-        if let Some(sound_file) = sound_file {
-            let decoded =
-                match DecodedSaySound::from_command_and_file(&say_command, &sound_file).await {
-                    Ok(decoded) => decoded,
+        let source = { Source::get(&storage.read().unwrap(), &say_command.name) };
+        let Some(source) = source else {
+            prepared_sounds.push(None);
+            continue;
+        };
+        match DecodedSaySound::from_command_and_source(say_command, &source, Arc::clone(&cache))
+            .await
+        {
+            Ok(decoded) => {
+                let decoded = Arc::new(decoded);
+                cache.insert(say_command, Arc::clone(&decoded));
+                let volume = volume(&decoded);
+                prepared_sounds.push(Some(PreparedSaySound { decoded, volume }));
+            }
+            Err(e) => {
+                warn!("Error decoding: {e:?}");
+                prepared_sounds.push(None);
+            }
+        }
+    }
+
+    Ok(lay_out(&say_commands, prepared_sounds))
+}
+
+/// Gain that brings a sound measured at `loudness_lufs` to `target_lufs`. Synthetic and silent
+/// sounds are left as they are.
+fn loudness_gain(loudness_lufs: Option<f64>, target_lufs: f64) -> f32 {
+    loudness_lufs.map_or(1.0, |loudness| {
+        10f64.powf((target_lufs - loudness).min(MAX_LOUDNESS_BOOST_DB) / 20.0) as f32
+    })
+}
+
+/// Lays out `say_commands`, whose commands were prepared into `prepared_sounds` in source order,
+/// on a timeline relative to the start of the message.
+fn lay_out(
+    say_commands: &SayCommands,
+    prepared_sounds: Vec<Option<PreparedSaySound>>,
+) -> Vec<ScheduledSaySound> {
+    let mut timeline = Vec::new();
+    schedule(
+        say_commands,
+        Duration::ZERO,
+        &mut prepared_sounds.into_iter(),
+        &mut timeline,
+    );
+    // Nothing starting this late would be heard before playback is cut off anyway.
+    timeline.retain(|scheduled| scheduled.offset < MAX_PLAYABLE_DURATION);
+    timeline.sort_by_key(|scheduled| scheduled.offset);
+    timeline
+}
+
+/// Mixes `say_commands` offline into a 16-bit wav file, exactly as they are played in a voice
+/// channel with the default configuration. They are sanitized as in playback, and sounds that are
+/// unknown or fail to decode are left out.
+pub fn render_say_commands(say_commands: &SayCommands, storage: &SoundStorage) -> Vec<u8> {
+    render_say_commands_with(
+        say_commands,
+        storage,
+        DEFAULT_LOUDNESS_TARGET,
+        &limiter(DEFAULT_CLIP_THRESHOLD, DEFAULT_SHARPNESS),
+    )
+}
+
+/// Like [`render_say_commands`], but with the loudness target and limiter of `configs`.
+pub fn render_say_commands_with_configs(
+    say_commands: &SayCommands,
+    storage: &SoundStorage,
+    configs: &Configs,
+) -> Vec<u8> {
+    render_say_commands_with(
+        say_commands,
+        storage,
+        configs.get_loudness_target(),
+        &limiter(configs.get_clip_threshold(), configs.get_sharpness()),
+    )
+}
+
+fn render_say_commands_with(
+    say_commands: &SayCommands,
+    storage: &SoundStorage,
+    loudness_target: f64,
+    limiter: &Limiter,
+) -> Vec<u8> {
+    mix(
+        lay_out_offline(say_commands, storage, loudness_target),
+        limiter,
+    )
+    .to_pcm16_wav()
+}
+
+/// Renders and lays out `say_commands` without the sound cache.
+fn lay_out_offline(
+    say_commands: &SayCommands,
+    storage: &SoundStorage,
+    loudness_target: f64,
+) -> Vec<ScheduledSaySound> {
+    let mut say_commands = say_commands.clone();
+    say_commands.sanitize();
+    say_commands.resolve_choices(storage, None);
+    say_commands.resolve_markers(storage);
+
+    let mut rendered: HashMap<SayCommand, Arc<DecodedSaySound>> = HashMap::new();
+    let mut prepared_sounds = Vec::new();
+    for say_command in say_commands.iter() {
+        let key = SaySoundCache::key(say_command);
+        let decoded = match rendered.get(&key) {
+            Some(decoded) => Some(Arc::clone(decoded)),
+            None => Source::get(storage, &say_command.name).and_then(|source| {
+                match render(say_command, &source) {
+                    Ok(audio) => {
+                        let decoded = Arc::new(DecodedSaySound::new(say_command, &source, audio));
+                        rendered.insert(key, Arc::clone(&decoded));
+                        Some(decoded)
+                    }
                     Err(e) => {
                         warn!("Error decoding: {e:?}");
-                        continue;
+                        None
                     }
-                };
-            let decoded = Arc::new(decoded);
-            cache.insert(say_command, Arc::clone(&decoded));
+                }
+            }),
+        };
+        prepared_sounds.push(decoded.map(|decoded| {
+            let volume = say_command.volume as f32 / 100.0
+                * loudness_gain(decoded.loudness_lufs, loudness_target);
+            PreparedSaySound { decoded, volume }
+        }));
+    }
+
+    lay_out(&say_commands, prepared_sounds)
+}
+
+/// Mixes `say_commands` into a 16-bit wav file as [`play_say_commands`] plays them, with the
+/// sound cache and configuration of `ctx`. Unlike [`render_say_commands`], sounds are looked up
+/// without holding on to the [`SoundStorage`] while they are decoded and mixed.
+///
+/// Returns `None` without mixing if the file would be larger than `max_bytes`.
+pub async fn render_say_commands_in_context(
+    mut say_commands: SayCommands,
+    ctx: &Context,
+    max_bytes: usize,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    say_commands.sanitize();
+    let configs = ctx
+        .data
+        .read()
+        .await
+        .get::<Configs>()
+        .context("Could not get Configs")?
+        .clone();
+    let limiter = {
+        let configs = configs.read().unwrap();
+        limiter(configs.get_clip_threshold(), configs.get_sharpness())
+    };
+
+    let timeline = process_say_commands(say_commands, ctx).await?;
+    if mixed_wav_len(&timeline) > max_bytes {
+        return Ok(None);
+    }
+    let wav = tokio::task::spawn_blocking(move || mix(timeline, &limiter).to_pcm16_wav()).await?;
+    Ok(Some(wav))
+}
+
+/// Limiter for mixes, which are played at [`VOLUME`]. The clip threshold and sharpness are levels
+/// in the voice channel, so they are scaled to the level of the mix.
+fn limiter(clip_threshold: f32, sharpness: f32) -> Limiter {
+    Limiter::new(clip_threshold / VOLUME, sharpness * VOLUME)
+}
+
+fn frames_at(time: Duration) -> usize {
+    (time.as_secs_f64() * OUTPUT_SAMPLE_RATE_HZ as f64).round() as usize
+}
 
-            decoded_sounds.push(decoded);
+/// Channel count and length in frames of the mix of `timeline`.
+fn mix_shape(timeline: &[ScheduledSaySound]) -> (usize, usize) {
+    let channels = timeline
+        .iter()
+        .map(|scheduled| scheduled.sound.decoded.decoded_data.channels)
+        .max()
+        .unwrap_or(1);
+    let end = timeline
+        .iter()
+        .map(|scheduled| scheduled.offset + scheduled.sound.decoded.decoded_data.duration())
+        .max()
+        .unwrap_or_default()
+        .min(MAX_PLAYABLE_DURATION);
+    (channels, frames_at(end))
+}
+
+/// Size of the 16-bit wav file of the mix of `timeline`, known before mixing.
+fn mixed_wav_len(timeline: &[ScheduledSaySound]) -> usize {
+    let (channels, frames) = mix_shape(timeline);
+    dsp::WAV_HEADER_BYTES + frames * channels * 2
+}
+
+/// Adds up the sounds of `timeline` at their offsets, cut off where playback would be, and
+/// limits the result so that overlapping sounds do not clip.
+fn mix(timeline: Vec<ScheduledSaySound>, limiter: &Limiter) -> Audio {
+    let (channels, frames) = mix_shape(&timeline);
+    let mut samples = vec![0.0; frames * channels];
+    for ScheduledSaySound { offset, sound } in timeline {
+        let audio = &sound.decoded.decoded_data;
+        let out = samples[frames_at(offset) * channels..].chunks_exact_mut(channels);
+        for (out, frame) in out.zip(audio.samples.chunks_exact(audio.channels)) {
+            for (ch, sample) in out.iter_mut().enumerate() {
+                // Mono sounds play on every channel, as in the voice channel.
+                *sample += frame[ch.min(audio.channels - 1)] * sound.volume;
+            }
         }
     }
+    let mut audio = Audio::new(OUTPUT_SAMPLE_RATE_HZ, channels, samples);
+    limiter.apply(&mut audio);
+    audio
+}
 
-    Ok(decoded_sounds)
+/// Lays out `say_commands` starting at `start`, taking one entry of `prepared_sounds` per
+/// command.
+///
+/// Returns the instant the node following this sequence starts at and the instant the last sound
+/// of this sequence stops playing.
+fn schedule(
+    say_commands: &SayCommands,
+    start: Duration,
+    prepared_sounds: &mut impl Iterator<Item = Option<PreparedSaySound>>,
+    timeline: &mut Vec<ScheduledSaySound>,
+) -> (Duration, Duration) {
+    let mut cursor = start;
+    let mut end = start;
+    for node in say_commands.nodes() {
+        let (node_cursor, node_end) = schedule_node(node, cursor, prepared_sounds, timeline);
+        cursor = node_cursor;
+        end = cmp::max(end, node_end);
+    }
+    (cursor, end)
+}
+
+fn schedule_node(
+    node: &SayNode,
+    start: Duration,
+    prepared_sounds: &mut impl Iterator<Item = Option<PreparedSaySound>>,
+    timeline: &mut Vec<ScheduledSaySound>,
+) -> (Duration, Duration) {
+    match node {
+        // Choices are resolved into commands before scheduling.
+        SayNode::Choice(_) => (start, start),
+        SayNode::Command(cmd) => {
+            let Some(sound) = prepared_sounds.next().flatten() else {
+                return (start, start);
+            };
+            let start = cmd.at.map_or(start, |at| Duration::from_millis(at as u64));
+            let cursor = start + sound.decoded.blocking_duration;
+            let end = start + sound.decoded.playing_duration;
+            timeline.push(ScheduledSaySound {
+                offset: start,
+                sound,
+            });
+            (cursor, end)
+        }
+        SayNode::Layer(layer) => {
+            let mut lanes_cursor = start;
+            let mut lanes_end = start;
+            for lane in &layer.lanes {
+                let (lane_cursor, lane_end) = schedule(lane, start, prepared_sounds, timeline);
+                lanes_cursor = cmp::max(lanes_cursor, lane_cursor);
+                lanes_end = cmp::max(lanes_end, lane_end);
+            }
+            let cursor = match layer.action {
+                Action::Synthesize => lanes_cursor,
+                Action::Concat => lanes_end,
+            };
+            (cursor, lanes_end)
+        }
+        SayNode::Repeat(repeat) => {
+            // Commands are decoded once, no matter how often they are repeated.
+            let sounds: Vec<_> = prepared_sounds
+                .by_ref()
+                .take(repeat.node.commands().len())
+                .collect();
+            let mut cursor = start;
+            let mut end = start;
+            for _ in 0..repeat.count {
+                // Later repetitions would not be heard before playback is cut off anyway.
+                if cursor >= MAX_PLAYABLE_DURATION {
+                    break;
+                }
+                let (node_cursor, node_end) =
+                    schedule_node(&repeat.node, cursor, &mut sounds.iter().cloned(), timeline);
+                cursor = node_cursor;
+                end = cmp::max(end, node_end);
+            }
+            (cursor, end)
+        }
+        SayNode::Seq(seq) => {
+            let sounds: Vec<_> = prepared_sounds.by_ref().take(seq.tracks.len()).collect();
+            let step_duration = seq.step_duration();
+            let mut end = start;
+            for (track, sound) in seq.tracks.iter().zip(sounds) {
+                let Some(sound) = sound else {
+                    continue;
+                };
+                for (step, _) in track.steps.iter().enumerate().filter(|(_, hit)| **hit) {
+                    let offset = start + step_duration * step as u32;
+                    end = cmp::max(end, offset + sound.decoded.playing_duration);
+                    timeline.push(ScheduledSaySound {
+                        offset,
+                        sound: sound.clone(),
+                    });
+                }
+            }
+            let pattern_end = start + step_duration * seq.len() as u32;
+            let cursor = match seq.action {
+                Action::Synthesize => pattern_end,
+                Action::Concat => cmp::max(pattern_end, end),
+            };
+            (cursor, cmp::max(end, pattern_end))
+        }
+    }
 }
 
+/// Plays `say_commands` in `guild_id`. Returns once playback has started, with how long it lasts.
+///
+/// The message is limited to the clip threshold as a whole, but it is still a track of its own:
+/// messages played over each other are only added up by the voice channel and can exceed it.
 #[tracing::instrument]
 pub async fn play_say_commands(
     say_commands: SayCommands,
     ctx: &Context,
     guild_id: GuildId,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Duration> {
     let manager = songbird::get(ctx)
         .await
         .context("Songbird Voice client placed in at initialization.")?
@@ -212,68 +834,335 @@ pub async fn play_say_commands(
         .get(guild_id)
         .context("Could not get the call handler for the given guild")?;
 
-    let decoded_sounds = process_say_commands(say_commands, ctx).await?;
-
-    let mut track_handles = Vec::new();
-    let mut estimated_duration = Duration::from_secs(0);
-    let mut elapsed = Duration::from_secs(0);
-    let timeout_result = {
-        let task = send_tracks(
-            decoded_sounds,
-            handler_lock,
-            &mut track_handles,
-            &mut estimated_duration,
-            &mut elapsed,
-        );
-        tokio::pin!(task);
-        tokio::time::timeout(MAX_PLAYABLE_DURATION, &mut task).await
+    let configs = ctx
+        .data
+        .read()
+        .await
+        .get::<Configs>()
+        .context("Could not get Configs")?
+        .clone();
+    let limiter = {
+        let configs = configs.read().unwrap();
+        limiter(configs.get_clip_threshold(), configs.get_sharpness())
     };
-    match timeout_result {
-        Ok(_) => {
-            if estimated_duration > MAX_PLAYABLE_DURATION {
-                let sleep_dur = MAX_PLAYABLE_DURATION - elapsed;
-                tokio::time::sleep(sleep_dur).await;
-                for track_handle in track_handles.iter() {
-                    track_handle.stop().ok();
-                }
-            }
-        }
-        Err(_) => {
-            for track_handle in track_handles.iter() {
-                track_handle.stop().ok();
-            }
-        }
+
+    let timeline = process_say_commands(say_commands, ctx).await?;
+    if timeline.is_empty() {
+        return Ok(Duration::ZERO);
     }
 
-    Ok(())
+    // The whole message is played as one track so that the limiter sees every sound at once.
+    let (duration, wav) = tokio::task::spawn_blocking(move || {
+        let audio = mix(timeline, &limiter);
+        (audio.duration(), audio.to_wav())
+    })
+    .await?;
+    let track = Memory::new(wav.into()).await?;
+    play_sound(&track, handler_lock, VOLUME).await;
+    Ok(duration)
+}
+
+pub async fn play_sound(mem: &Memory, handler_lock: Arc<Mutex<Call>>, volume: f32) -> TrackHandle {
+    let mut handler = handler_lock.lock().await;
+
+    handler.play(Track::new(mem.new_handle().into()).volume(volume))
 }
 
-async fn send_tracks(
-    decoded_sounds: Vec<Arc<DecodedSaySound>>,
-    handler_lock: Arc<Mutex<Call>>,
-    track_handles: &mut Vec<TrackHandle>,
-    estimated_duration: &mut Duration,
-    elapsed: &mut Duration,
-) -> anyhow::Result<()> {
-    for decoded_sound in decoded_sounds {
-        *estimated_duration = cmp::max(
-            *estimated_duration,
-            *elapsed + decoded_sound.playing_duration,
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, str::FromStr};
+
+    use super::*;
+
+    fn storage() -> SoundStorage {
+        SoundStorage::load(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("..")
+                .join("tests/sound"),
+        )
+    }
+
+    fn render_all(saycmds: &str, source: &Source) -> Vec<Audio> {
+        SayCommands::from_str(saycmds)
+            .unwrap()
+            .iter()
+            .map(|cmd| render(cmd, source).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_render_lengths() {
+        let source = Source::File(storage().get("sainou").unwrap());
+        let full = dsp::decode_file(&storage().get("sainou").unwrap().path).unwrap();
+        let full_frames = full.frames() * 48000 / full.sample_rate_hz as usize;
+
+        let rendered = render_all(
+            "sainou; sainou d0.5; sainou d0.5 200; sainou d0.5 50; sainou d0.5 p+12st; \
+             sainou d0.5 p-5st 150; sainou s-0.5; sainou s-0:30",
+            &source,
         );
+        let frames: Vec<_> = rendered.iter().map(Audio::frames).collect();
+        assert!(frames[0].abs_diff(full_frames) <= 1);
+        // The speed changes the length, the pitch does not.
+        assert_eq!(frames[1..6], [24000, 12000, 48000, 24000, 16000]);
+        assert_eq!(frames[6], 24000);
+        // Starting before the beginning of the sound starts at its beginning.
+        assert_eq!(frames[7], frames[0]);
+        assert!(rendered.iter().all(|audio| audio.sample_rate_hz == 48000));
+    }
 
-        let track_handle =
-            play_sound(&decoded_sound.decoded_data, handler_lock.clone(), VOLUME).await;
+    #[test]
+    fn test_render_bounds() {
+        let storage = storage();
+        let saycmds =
+            SayCommands::from_str("_ d20 10; sine440 d20 10 rev; sine440 d1 p-48st").unwrap();
+        let cmds: Vec<_> = saycmds.iter().collect();
+        let render_cmd = |cmd: &SayCommand| {
+            let source = Source::get(&storage, &cmd.name).unwrap();
+            render(cmd, &source).unwrap()
+        };
+
+        // Slowed down sounds are cut off where playback would be.
+        assert_eq!(render_cmd(cmds[0]).frames(), 180 * 48000);
+        assert_eq!(render_cmd(cmds[1]).frames(), 180 * 48000);
+        // Pitching down does not lengthen the sound.
+        assert_eq!(render_cmd(cmds[2]).frames(), 48000);
+    }
+
+    #[test]
+    fn test_render_pan() {
+        let source = Source::File(storage().get("sainou").unwrap());
+        let saycmds = SayCommands::from_str("sainou d1; sainou d1 L50; sainou d1 R30").unwrap();
+        let cmds: Vec<_> = saycmds.iter().collect();
 
-        *elapsed += decoded_sound.blocking_duration;
-        tokio::time::sleep(decoded_sound.blocking_duration).await;
+        let centered = render(cmds[0], &source).unwrap();
+        let left = render(cmds[1], &source).unwrap();
+        assert_eq!(left.channels, 2);
+        assert_eq!(left.frames(), centered.frames());
+        let peak = |audio: &Audio, ch: usize| {
+            audio
+                .samples
+                .iter()
+                .skip(ch)
+                .step_by(audio.channels)
+                .fold(0.0f32, |m, s| m.max(s.abs()))
+        };
+        // Mono sounds are upmixed, so both sides start out as the only channel.
+        let side = |ch: usize| peak(&centered, ch.min(centered.channels - 1));
+        assert!((peak(&left, 0) - side(0)).abs() < 1e-6);
+        assert!((peak(&left, 1) - side(1) * 0.5).abs() < 1e-6);
+
+        // Panned and centered commands are decoded separately.
+        assert_ne!(SaySoundCache::key(cmds[0]), SaySoundCache::key(cmds[1]));
 
-        (*track_handles).push(track_handle);
+        let right = render(cmds[2], &source).unwrap();
+        assert!((peak(&right, 0) - side(0) * 0.7).abs() < 1e-6);
+        assert!((peak(&right, 1) - side(1)).abs() < 1e-6);
     }
-    Ok(())
-}
 
-pub async fn play_sound(mem: &Memory, handler_lock: Arc<Mutex<Call>>, volume: f32) -> TrackHandle {
-    let mut handler = handler_lock.lock().await;
+    #[test]
+    fn test_render_synth() {
+        let storage = storage();
+        let saycmds = SayCommands::from_str("sine440 120; _ d0.5; noise s1 d2").unwrap();
+        let cmds: Vec<_> = saycmds.iter().collect();
 
-    handler.play(Track::new(mem.new_handle().into()).volume(volume))
+        let source = Source::get(&storage, &cmds[0].name).unwrap();
+        let audio = render(cmds[0], &source).unwrap();
+        assert_eq!(audio.channels, 1);
+        assert_eq!(audio.frames(), 40000);
+
+        let source = Source::get(&storage, &cmds[1].name).unwrap();
+        assert_eq!(source.duration(cmds[1]), Duration::from_millis(500));
+        let audio = render(cmds[1], &source).unwrap();
+        assert_eq!(audio.frames(), 24000);
+        assert!(audio.samples.iter().all(|&s| s == 0.0));
+
+        // The start of a synthetic sound does not shorten it.
+        let source = Source::get(&storage, &cmds[2].name).unwrap();
+        assert_eq!(source.duration(cmds[2]), Duration::from_secs(3));
+        assert_eq!(render(cmds[2], &source).unwrap().frames(), 96000);
+    }
+
+    #[test]
+    fn test_render_say_commands() {
+        let storage = storage();
+        let config_dir = tempfile::tempdir().unwrap();
+        let mut configs = Configs::load_or_create(config_dir.path().join("config.json")).unwrap();
+        // High enough for the tones below to be left as they are.
+        configs.set_clip_threshold("0.05").unwrap();
+        let mut hint = symphonia::core::probe::Hint::new();
+        hint.with_extension("wav");
+        let decode =
+            |wav: Vec<u8>| dsp::decode(Box::new(std::io::Cursor::new(wav)), &hint).unwrap();
+        let render_with = |saycmds: &str, configs: &Configs| {
+            let saycmds = SayCommands::from_str(saycmds).unwrap();
+            decode(render_say_commands_with_configs(
+                &saycmds, &storage, configs,
+            ))
+        };
+        let render = |saycmds: &str| render_with(saycmds, &configs);
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+
+        // `|` waits for the silence to end.
+        let audio = render("_ d1 | sine440 d0.5");
+        assert_eq!((audio.sample_rate_hz, audio.channels), (48000, 1));
+        assert_eq!(audio.frames(), 72000);
+        assert_eq!(peak(&audio.samples[..48000]), 0.0);
+        assert!(peak(&audio.samples[48000..]) > 0.2);
+
+        // `;` only waits `w`, so the tones overlap and add up.
+        let audio = render("sine440 d0.5 w0.2; sine440 d0.5");
+        assert_eq!(audio.frames(), 33600);
+        assert!(peak(&audio.samples[9600..24000]) > 0.3);
+        assert!(peak(&audio.samples[..9600]) <= 0.26);
+
+        // Unknown sounds are left out, and panned sounds make the whole mix stereo.
+        let audio = render("nosuchsound | sine440 d0.5 v50; sine440 d0.5 R100");
+        assert_eq!((audio.channels, audio.frames()), (2, 24000));
+        let left: Vec<_> = audio.samples.iter().copied().step_by(2).collect();
+        assert!(peak(&left) < 0.13);
+
+        // Stacked sounds are limited to the clip threshold, which is a level in the voice channel
+        // and thus applies to the mix divided by `VOLUME`. 16-bit samples may round up to it.
+        let stacked = "sine440 d0.5 w0; sine440 d0.5 w0; sine440 d0.5 w0; sine440 d0.5";
+        for (threshold, sharpness) in [("0.01", "250"), ("0.02", "100"), ("0.005", "10000")] {
+            configs.set_clip_threshold(threshold).unwrap();
+            configs.set_sharpness(sharpness).unwrap();
+            let ceiling = threshold.parse::<f32>().unwrap() / VOLUME + 1.0 / 32768.0;
+            let stacked_peak = peak(&render_with(stacked, &configs).samples);
+            assert!(stacked_peak <= ceiling, "{threshold}: {stacked_peak}");
+            assert!(stacked_peak > ceiling * 0.9, "{threshold}: {stacked_peak}");
+            let loud = peak(&render_with("dadeisan w0; sainou w0; dadeisan", &configs).samples);
+            assert!(loud <= ceiling, "{threshold}: {loud}");
+        }
+
+        // Each message is limited on its own, so two of them played over each other as separate
+        // tracks add up past the threshold.
+        let ceiling = DEFAULT_CLIP_THRESHOLD / VOLUME + 1.0 / 32768.0;
+        let message = decode(render_say_commands(
+            &SayCommands::from_str(stacked).unwrap(),
+            &storage,
+        ));
+        assert!(peak(&message.samples) <= ceiling);
+        let overlapping: Vec<_> = message.samples.iter().map(|s| s * 2.0).collect();
+        assert!(peak(&overlapping) > ceiling);
+    }
+
+    #[test]
+    fn test_render_say_commands_sanitizes() {
+        // Unclamped, a speed of 0 and `p0` would resample at an infinite ratio and panic, and the
+        // repeat would be scheduled billions of times.
+        let saycmds = SayCommands::from_str("sainou 0 p0; sine440 d0.01*4294967295").unwrap();
+        let wav = render_say_commands(&saycmds, &storage());
+        assert!(wav.len() > 44);
+    }
+
+    #[test]
+    fn test_mixed_wav_len() {
+        let limiter = limiter(DEFAULT_CLIP_THRESHOLD, DEFAULT_SHARPNESS);
+        for saycmds in ["sainou; d", "sine440 d0.5 R100 | sainou 50", "nosuchsound"] {
+            let saycmds = SayCommands::from_str(saycmds).unwrap();
+            let timeline = lay_out_offline(&saycmds, &storage(), DEFAULT_LOUDNESS_TARGET);
+            let len = mixed_wav_len(&timeline);
+            assert_eq!(
+                mix(timeline, &limiter).to_pcm16_wav().len(),
+                len,
+                "{saycmds}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_lay_out_repeats() {
+        // Repetitions starting after playback is cut off are not scheduled, however many there
+        // are.
+        let saycmds = SayCommands::from_str("(seq 120bpm a:....*4294967295)*4294967295").unwrap();
+        assert!(lay_out(&saycmds, vec![None]).is_empty());
+    }
+
+    #[test]
+    fn test_loudness_gain() {
+        assert_eq!(loudness_gain(None, -16.0), 1.0);
+        assert_eq!(loudness_gain(Some(-16.0), -16.0), 1.0);
+        assert!((loudness_gain(Some(-10.0), -16.0) - 0.501).abs() < 1e-3);
+        assert!((loudness_gain(Some(-22.0), -16.0) - 1.995).abs() < 1e-3);
+        // Near-silent sounds get at most 12 dB.
+        assert!((loudness_gain(Some(-60.0), -16.0) - 3.981).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let sound_dir = tempfile::tempdir().unwrap();
+        let sound_path = sound_dir.path().join("sainou.mp3");
+        fs::copy(fixtures.join("sainou.mp3"), &sound_path).unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let renders = || fs::read_dir(cache_dir.path()).unwrap().count();
+
+        let saycmds =
+            SayCommands::from_str("sainou d0.5; Sainou d0.5 v50 @t1; sainou d0.2; sainou d0.1")
+                .unwrap();
+        let cmds: Vec<_> = saycmds.iter().collect();
+        let source = || Source::File(SoundStorage::load(sound_dir.path()).get("sainou").unwrap());
+
+        let cache = SaySoundCache::new(1024 * 1024)
+            .with_disk(cache_dir.path(), u64::MAX)
+            .unwrap();
+        let audio = cache.load_or_render(cmds[0], &source()).unwrap();
+        assert_eq!(renders(), 1);
+        // Volume, start time and case do not change the render.
+        assert_eq!(cache.load_or_render(cmds[1], &source()).unwrap(), audio);
+        assert_eq!(renders(), 1);
+        cache.load_or_render(cmds[2], &source()).unwrap();
+        assert_eq!(renders(), 2);
+
+        // Renders survive a restart.
+        let cache = SaySoundCache::new(1024 * 1024)
+            .with_disk(cache_dir.path(), audio.to_wav().len() as u64)
+            .unwrap();
+        assert_eq!(cache.load_or_render(cmds[0], &source()).unwrap(), audio);
+
+        // A replaced sound is rendered again.
+        fs::copy(fixtures.join("dadeisan.mp3"), &sound_path).unwrap();
+        assert_ne!(cache.load_or_render(cmds[0], &source()).unwrap(), audio);
+
+        // Only the latest render fits in the budget.
+        cache.load_or_render(cmds[3], &source()).unwrap();
+        assert_eq!(renders(), 1);
+        let key = SaySoundCache::disk_key(cmds[3], &source());
+        assert!(cache_dir.path().join(format!("{key}.wav")).exists());
+
+        // Temporary files left behind by a crash are removed on start and by `clean`.
+        let stale = cache_dir.path().join(format!("{key}.0badf00d.tmp"));
+        fs::write(&stale, b"partial").unwrap();
+        let cache = SaySoundCache::new(1024 * 1024)
+            .with_disk(cache_dir.path(), u64::MAX)
+            .unwrap();
+        assert_eq!(renders(), 1);
+        fs::write(&stale, b"partial").unwrap();
+        cache.clean();
+        assert_eq!(renders(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let storage = storage();
+        let cache = Arc::new(SaySoundCache::new(64 * 1024 * 1024));
+        let saycmds = SayCommands::from_str("sainou d0.1; dadeisan d0.1").unwrap();
+        for cmd in saycmds.iter() {
+            let source = Source::get(&storage, &cmd.name).unwrap();
+            let decoded =
+                DecodedSaySound::from_command_and_source(cmd, &source, Arc::clone(&cache))
+                    .await
+                    .unwrap();
+            cache.insert(cmd, Arc::new(decoded));
+        }
+
+        cache.invalidate(&["Sainou".to_owned()]);
+        let cmds: Vec<_> = saycmds.iter().collect();
+        assert!(cache.get(cmds[0]).is_none());
+        assert!(cache.get(cmds[1]).is_some());
+    }
 }
@@ -1,9 +1,9 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     ffi::OsStr,
     fs,
     path::{Path, PathBuf},
-    sync::{Arc, OnceLock, RwLock},
+    sync::{Arc, Mutex, OnceLock, RwLock},
     time::{Duration, SystemTime},
 };
 
@@ -15,10 +15,166 @@ use notify::{
     Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
     event::{CreateKind, ModifyKind, RenameMode},
 };
-use rand::{SeedableRng, rngs::StdRng, seq::IteratorRandom};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::IteratorRandom};
+use serde::{Deserialize, Serialize};
 use serenity::prelude::TypeMapKey;
+use sha1::{Digest, Sha1};
 use tokio::{runtime::Handle, sync::mpsc};
 use tracing::{info, warn};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::dsp;
+
+/// Returns the key `name` is looked up by, so that names differing only in case, character
+/// width (`ＡＢＣ`, `ｻｲﾉｳ`) or kana script (`サイノウ`, `さいのう`) find the same sound.
+pub fn lookup_key(name: &str) -> String {
+    name.nfkc()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            // Katakana and their iteration marks sit 0x60 above the matching hiragana.
+            'ァ'..='ヶ' | 'ヽ'..='ヾ' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+            _ => c,
+        })
+        .collect()
+}
+
+/// A named part of a sound, played with `sound#marker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Marker {
+    pub start: Duration,
+    /// Up to the end of the sound if `None`.
+    pub duration: Option<Duration>,
+}
+
+/// An entry of a marker sidecar, with times in seconds.
+#[derive(Debug, Deserialize)]
+struct MarkerEntry {
+    start: f64,
+    #[serde(default)]
+    duration: Option<f64>,
+}
+
+impl TryFrom<MarkerEntry> for Marker {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: MarkerEntry) -> Result<Self, Self::Error> {
+        Ok(Self {
+            start: Duration::try_from_secs_f64(entry.start)?,
+            duration: entry
+                .duration
+                .map(Duration::try_from_secs_f64)
+                .transpose()?,
+        })
+    }
+}
+
+/// Returns the path of the marker sidecar of the sound at `path`, e.g. `clip.markers.json` for
+/// `clip.mp3`.
+fn marker_sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("markers.json")
+}
+
+/// Returns the path of the sound a marker sidecar at `path` belongs to.
+fn marker_sidecar_owner(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let stem = file_name.strip_suffix(".markers.json")?;
+    Some(path.with_file_name(format!("{stem}.mp3")))
+}
+
+/// Loads the markers of the sound at `path`, keyed by lowercased name. A missing sidecar means
+/// no markers.
+fn load_markers(path: &Path) -> anyhow::Result<BTreeMap<String, Marker>> {
+    let sidecar = marker_sidecar_path(path);
+    if !sidecar.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let entries: BTreeMap<String, MarkerEntry> = serde_json::from_slice(&fs::read(&sidecar)?)
+        .with_context(|| format!("Failed to parse {sidecar:?}"))?;
+    entries
+        .into_iter()
+        .map(|(name, entry)| Ok((lookup_key(&name), entry.try_into()?)))
+        .collect()
+}
+
+/// The loudness of a sound, kept since measuring it decodes the whole file.
+#[derive(Debug, Serialize, Deserialize)]
+struct LoudnessEntry {
+    /// Integrated loudness in LUFS, or `None` for silence.
+    loudness_lufs: Option<f64>,
+}
+
+/// What is learned by reading or decoding whole sound files, kept across reloads of the
+/// [`SoundStorage`] and, with [`Self::with_dir`], across restarts.
+#[derive(Debug, Default)]
+pub struct MeasurementCache {
+    /// Where loudness entries are stored, named after the content hash of the sound.
+    dir: Option<PathBuf>,
+    /// Content hashes by path, valid as long as the length and modification time of the file do
+    /// not change.
+    hashes: Mutex<HashMap<PathBuf, (u64, SystemTime, String)>>,
+}
+
+impl MeasurementCache {
+    /// Keeps loudness entries in `dir`.
+    pub fn with_dir(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("Could not create {dir:?}"))?;
+        Ok(Self {
+            dir: Some(dir),
+            ..Default::default()
+        })
+    }
+
+    /// Returns the hex SHA-1 of the file at `path`, hashing it only if it changed since it was
+    /// last hashed.
+    fn content_hash(&self, path: &Path) -> anyhow::Result<String> {
+        let metadata = fs::metadata(path)?;
+        let (len, modified) = (metadata.len(), metadata.modified()?);
+        if let Some((hashed_len, hashed_modified, hash)) = self.hashes.lock().unwrap().get(path)
+            && (*hashed_len, *hashed_modified) == (len, modified)
+        {
+            return Ok(hash.clone());
+        }
+        let hash = format!("{:x}", Sha1::digest(fs::read(path)?));
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), (len, modified, hash.clone()));
+        Ok(hash)
+    }
+
+    /// Measures the loudness of the sound at `path`, or reads it from the entry of the same
+    /// content if it was measured before.
+    fn loudness_lufs(&self, path: &Path, content_hash: &str) -> Option<f64> {
+        let entry_path = self
+            .dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{content_hash}.json")));
+        if let Some(ref entry_path) = entry_path
+            && let Ok(data) = fs::read(entry_path)
+            && let Ok(entry) = serde_json::from_slice::<LoudnessEntry>(&data)
+        {
+            return entry.loudness_lufs;
+        }
+
+        // A sound that cannot be decoded fails when it is played, but is still listed.
+        let loudness_lufs = match dsp::decode_file(path) {
+            Ok(audio) => dsp::integrated_loudness(&audio),
+            Err(e) => {
+                warn!("Error measuring the loudness of {path:?}: {e:?}");
+                return None;
+            }
+        };
+        if let Some(entry_path) = entry_path {
+            let data = serde_json::to_vec(&LoudnessEntry { loudness_lufs })
+                .expect("LoudnessEntry is always serializable");
+            if let Err(e) = fs::write(entry_path, data) {
+                warn!("Error saving the loudness of {path:?}: {e:?}");
+            }
+        }
+        loudness_lufs
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct Metadata {
@@ -27,6 +183,7 @@ struct Metadata {
     duration: Duration,
     updated_at: SystemTime,
     references: Vec<String>,
+    markers: BTreeMap<String, Marker>,
 }
 
 impl Metadata {
@@ -77,12 +234,19 @@ impl Metadata {
         references.sort_unstable();
         references.dedup();
 
+        // Broken markers should not make the sound itself unplayable.
+        let markers = load_markers(path.as_ref()).unwrap_or_else(|e| {
+            warn!("Error loading markers: {e:?}");
+            BTreeMap::new()
+        });
+
         Ok(Self {
             sample_rate_hz,
             channel_count,
             duration,
             updated_at,
             references,
+            markers,
         })
     }
 }
@@ -96,6 +260,12 @@ pub struct SoundFile {
     // files, metadata is not needed immediately, so wrap in OnceLock to delay
     // metadata retrieval.
     metadata: OnceLock<Metadata>,
+
+    // Hashing and measuring the loudness read the whole file, so they are left out of the
+    // metadata, shared between clones and cached in the storage the file belongs to.
+    content_hash: Arc<OnceLock<String>>,
+    loudness_lufs: Arc<OnceLock<Option<f64>>>,
+    measurements: Arc<MeasurementCache>,
 }
 
 impl SoundFile {
@@ -105,6 +275,9 @@ impl SoundFile {
             name: path.as_ref().file_stem().unwrap().to_string_lossy().into(),
             path: path.as_ref().into(),
             metadata: OnceLock::new(),
+            content_hash: Arc::default(),
+            loudness_lufs: Arc::default(),
+            measurements: Arc::default(),
         }
     }
 
@@ -121,6 +294,9 @@ impl SoundFile {
                 .into(),
             path: path.as_ref().into(),
             metadata: Metadata::load(path.as_ref())?.into(),
+            content_hash: Arc::default(),
+            loudness_lufs: Arc::default(),
+            measurements: Arc::default(),
         })
     }
 
@@ -153,6 +329,39 @@ impl SoundFile {
             .references
     }
 
+    pub fn marker(&self, name: impl AsRef<str>) -> Option<Marker> {
+        self.metadata
+            .get_or_init(|| self.load_unchecked())
+            .markers
+            .get(&lookup_key(name.as_ref()))
+            .copied()
+    }
+
+    /// Hex SHA-1 of the file, which changes whenever the sound is replaced.
+    ///
+    /// This may read the whole file, so call it off the async runtime.
+    pub fn content_hash(&self) -> &str {
+        self.content_hash.get_or_init(|| {
+            self.measurements
+                .content_hash(&self.path)
+                .unwrap_or_else(|e| {
+                    warn!("Error hashing {:?}: {e:?}", self.path);
+                    String::new()
+                })
+        })
+    }
+
+    /// Integrated loudness in LUFS, or `None` for silence.
+    ///
+    /// Unless it was measured before, this decodes the whole file, so call it off the async
+    /// runtime.
+    pub fn loudness_lufs(&self) -> Option<f64> {
+        *self.loudness_lufs.get_or_init(|| {
+            self.measurements
+                .loudness_lufs(&self.path, self.content_hash())
+        })
+    }
+
     fn load_unchecked(&self) -> Metadata {
         Metadata::load(&self.path)
             .unwrap_or_else(|_| panic!("Failed to load the metadata of {:?}", self.path))
@@ -168,6 +377,7 @@ impl TryFrom<SoundFile> for ssspam_proto::ss::SaySound {
             sources: sound.references().to_vec(),
             duration: Some(prost_types::Duration::try_from(sound.duration())?),
             created: Some(sound.updated_at().into()),
+            loudness_lufs: sound.loudness_lufs(),
         })
     }
 }
@@ -191,35 +401,53 @@ where
                     .ok()
             })
             .collect();
-        ssspam_proto::ss::Sounds { sounds }
+        ssspam_proto::ss::Sounds {
+            sounds,
+            ..Default::default()
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct SoundStorage {
-    /// Lowercased name to [`Sound`].
+    /// [`lookup_key`] of the name to [`Sound`].
     sounds: BTreeMap<String, SoundFile>,
 
     pub dir: PathBuf,
+
+    /// Shared with every file of the storage.
+    measurements: Arc<MeasurementCache>,
 }
 
 impl SoundStorage {
     pub fn load<P: AsRef<Path>>(dir: P) -> Self {
-        let mut sounds = BTreeMap::new();
+        Self::load_with(dir, Arc::default())
+    }
+
+    /// Keeps what is measured of the sounds in `dir`, so that it survives restarts.
+    pub fn with_measurement_dir(self, dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        Ok(Self::load_with(
+            &self.dir,
+            Arc::new(MeasurementCache::with_dir(dir)?),
+        ))
+    }
+
+    fn load_with<P: AsRef<Path>>(dir: P, measurements: Arc<MeasurementCache>) -> Self {
+        let mut storage = Self {
+            sounds: BTreeMap::new(),
+            dir: dir.as_ref().into(),
+            measurements,
+        };
         for path in
             (glob(&format!("{}/**/*.mp3", dir.as_ref().to_string_lossy())).unwrap()).flatten()
         {
-            let sound = SoundFile::new_unchecked(path);
-            sounds.insert(sound.name.to_lowercase(), sound);
-        }
-        Self {
-            sounds,
-            dir: dir.as_ref().into(),
+            storage.add(SoundFile::new_unchecked(path));
         }
+        storage
     }
 
     pub fn reload(&mut self) {
-        *self = Self::load(&self.dir);
+        *self = Self::load_with(&self.dir, Arc::clone(&self.measurements));
     }
 
     pub fn files(&self) -> impl Iterator<Item = &SoundFile> {
@@ -227,24 +455,54 @@ impl SoundStorage {
     }
 
     pub fn get(&self, name: impl AsRef<str>) -> Option<SoundFile> {
-        self.sounds.get(&name.as_ref().to_lowercase()).cloned()
+        self.sounds.get(&lookup_key(name.as_ref())).cloned()
+    }
+
+    /// Looks up `name`, which may point at a marker of the sound as in `sound#marker`.
+    pub fn get_marked(&self, name: impl AsRef<str>) -> Option<(SoundFile, Option<Marker>)> {
+        // Normalizing first also splits at a full-width `＃`.
+        match lookup_key(name.as_ref()).split_once('#') {
+            Some((name, marker)) => {
+                let file = self.get(name)?;
+                let marker = file.marker(marker)?;
+                Some((file, Some(marker)))
+            }
+            None => self.get(name).map(|file| (file, None)),
+        }
     }
 
     fn remove(&mut self, name: impl AsRef<str>) -> Option<SoundFile> {
-        self.sounds.remove(&name.as_ref().to_lowercase())
+        self.sounds.remove(&lookup_key(name.as_ref()))
     }
 
-    fn add(&mut self, sound: SoundFile) -> Option<SoundFile> {
-        self.sounds.insert(sound.name.to_lowercase(), sound)
+    /// Adds `sound`, warning when it shadows another file whose name normalizes to the same key.
+    fn add(&mut self, mut sound: SoundFile) -> Option<SoundFile> {
+        sound.measurements = Arc::clone(&self.measurements);
+        let path = sound.path.clone();
+        let replaced = self.sounds.insert(lookup_key(&sound.name), sound);
+        if let Some(ref other) = replaced
+            && other.path != path
+        {
+            warn!(
+                "{:?} and {:?} have the same normalized name, so only the latter can be played",
+                other.path, path
+            );
+        }
+        replaced
     }
 
     pub fn get_random(&self) -> Option<SoundFile> {
         let mut rng: StdRng = SeedableRng::from_entropy();
-        self.sounds.values().choose(&mut rng).cloned()
+        self.get_random_with(&mut rng)
+    }
+
+    /// Like [`Self::get_random`], but draws from `rng` so that the pick can be reproduced.
+    pub fn get_random_with(&self, rng: &mut impl Rng) -> Option<SoundFile> {
+        self.sounds.values().choose(rng).cloned()
     }
 
     pub fn calc_similarities(&self, query: impl AsRef<str>) -> Vec<(f64, SoundFile)> {
-        let query = query.as_ref().to_lowercase();
+        let query = lookup_key(query.as_ref());
         let mut sims: Vec<_> = self
             .sounds
             .iter()
@@ -283,6 +541,14 @@ pub async fn watch_sound_storage(storage: Arc<RwLock<SoundStorage>>) {
     }
 
     while let Some(Ok(event)) = rx.recv().await {
+        // Markers are part of the metadata, so any change to a sidecar reloads its sound.
+        if let Some(sound_path) = marker_sidecar_owner(&event.paths[0]) {
+            if let Ok(sound) = SoundFile::new_checked(&sound_path) {
+                let mut storage = storage.write().unwrap();
+                storage.add(sound);
+            }
+            continue;
+        }
         if event.paths[0].extension() != Some(OsStr::new("mp3")) {
             continue;
         }
@@ -373,6 +639,80 @@ mod test {
         assert_eq!(sound.channel_count(), 2);
     }
 
+    #[test]
+    fn test_loudness() {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let sound_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let path = sound_dir.path().join("sainou.mp3");
+        fs::copy(fixtures.join("sainou.mp3"), &path).unwrap();
+        let load = || {
+            SoundStorage::load(sound_dir.path())
+                .with_measurement_dir(cache_dir.path())
+                .unwrap()
+        };
+
+        let sound = load().get("sainou").unwrap();
+        let loudness = sound.clone().loudness_lufs().unwrap();
+        assert!((-28.0..-27.0).contains(&loudness), "{loudness}");
+        assert_eq!(sound.loudness_lufs.get(), Some(&Some(loudness)));
+        // Nothing is written next to the sounds.
+        assert_eq!(fs::read_dir(sound_dir.path()).unwrap().count(), 1);
+
+        // Later loads read the entry of the same content instead of decoding.
+        let entry_path = cache_dir
+            .path()
+            .join(format!("{}.json", sound.content_hash()));
+        let entry: LoudnessEntry = serde_json::from_slice(&fs::read(&entry_path).unwrap()).unwrap();
+        assert_eq!(entry.loudness_lufs, Some(loudness));
+        let entry = LoudnessEntry {
+            loudness_lufs: Some(-1.0),
+        };
+        fs::write(&entry_path, serde_json::to_vec(&entry).unwrap()).unwrap();
+        assert_eq!(load().get("sainou").unwrap().loudness_lufs(), Some(-1.0));
+
+        // A replaced sound is hashed and measured again, also after a reload.
+        let mut storage = load();
+        let hash = storage.get("sainou").unwrap().content_hash().to_owned();
+        fs::copy(fixtures.join("dadeisan.mp3"), &path).unwrap();
+        storage.reload();
+        let replaced = storage.get("sainou").unwrap();
+        assert_ne!(replaced.content_hash(), hash);
+        assert!(
+            replaced
+                .loudness_lufs()
+                .is_some_and(|loudness| loudness != -1.0)
+        );
+    }
+
+    #[test]
+    fn test_markers() {
+        let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let storage = SoundStorage::load(&sound_dir);
+        let (file, marker) = storage.get_marked("Sainou#Intro").unwrap();
+        assert_eq!(file.name, "sainou");
+        assert_eq!(
+            marker,
+            Some(Marker {
+                start: Duration::from_millis(200),
+                duration: Some(Duration::from_millis(500)),
+            })
+        );
+        assert_eq!(storage.get_marked("sainou").unwrap().1, None);
+        assert!(storage.get_marked("sainou#nothing").is_none());
+        assert!(storage.get_marked("d#intro").is_none());
+
+        assert_eq!(
+            marker_sidecar_owner(&sound_dir.join("sainou.markers.json")),
+            Some(sound_dir.join("sainou.mp3"))
+        );
+        assert_eq!(marker_sidecar_owner(&sound_dir.join("sainou.mp3")), None);
+    }
+
     #[test]
     fn test_sound_storage() {
         let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
@@ -401,6 +741,45 @@ mod test {
         assert!(storage.get_random().is_none());
     }
 
+    #[test]
+    fn test_lookup_key() {
+        assert_eq!(lookup_key("Sainou"), "sainou");
+        assert_eq!(lookup_key("ＳＡＩＮＯＵ！"), "sainou!");
+        assert_eq!(lookup_key("ｻｲﾉｳ"), "さいのう");
+        assert_eq!(lookup_key("サイノウ"), "さいのう");
+        assert_eq!(lookup_key("ｶﾞｯﾂ"), "がっつ");
+        assert_eq!(lookup_key("漢字ー"), "漢字ー");
+    }
+
+    #[test]
+    fn test_normalized_lookup() {
+        let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let storage = SoundStorage::load(&sound_dir);
+        assert_eq!(storage.get("ＳＡＩＮＯＵ").unwrap().name, "sainou");
+        assert!(
+            storage
+                .get_marked("ｓａｉｎｏｕ＃ＩＮＴＲＯ")
+                .unwrap()
+                .1
+                .is_some()
+        );
+
+        // Files whose names normalize to the same key shadow each other.
+        let temp_dir = tempfile::tempdir().unwrap();
+        for name in ["サイノウ", "さいのう"] {
+            fs::copy(
+                sound_dir.join("sainou.mp3"),
+                temp_dir.path().join(format!("{name}.mp3")),
+            )
+            .unwrap();
+        }
+        let storage = SoundStorage::load(&temp_dir);
+        assert_eq!(storage.len(), 1);
+        assert!(storage.get("ｻｲﾉｳ").is_some());
+    }
+
     #[test]
     fn test_calc_similarities() {
         let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
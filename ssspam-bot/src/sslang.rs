@@ -1,35 +1,281 @@
-use std::str::FromStr;
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::HashMap,
+    hash::Hash,
+    ops::Range,
+    str::FromStr,
+    time::Duration,
+};
 
 use nom::{
-    Finish, IResult,
+    IResult, Offset,
     branch::alt,
-    bytes::complete::{tag, take_till, take_while1},
-    character::complete::{char, multispace0, u32},
-    combinator::{eof, map, opt},
-    error::{Error, ParseError},
-    multi::{many0, many1},
+    bytes::complete::{tag, tag_no_case, take_till, take_while1},
+    character::complete::{char, i32, multispace0, multispace1, one_of, u32},
+    combinator::{consumed, eof, map, map_opt, map_res, opt, peek, recognize, verify},
+    error::ParseError,
+    multi::{many0, separated_list1},
     number::complete::double,
-    sequence::{delimited, pair, preceded},
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
+};
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+use crate::{
+    SoundFile, SoundStorage,
+    audio_filter::AudioFilter,
+    sound::{Marker, lookup_key},
+    synth::Synth,
 };
 
+/// Number of "did you mean" candidates attached to an unknown sound.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Maximum number of commands a message may play once repetitions are expanded.
+pub const MAX_EXPANDED_COMMANDS: usize = 100;
+
+/// Maximum number of macro invocations expanded in a single message.
+pub const MAX_MACRO_EXPANSIONS: usize = 64;
+
+/// Maximum number of groups nested in one another, which keeps every recursion over a message
+/// shallow.
+pub const MAX_GROUP_DEPTH: usize = 16;
+
+/// Fastest tempo accepted by `seq`.
+const MAX_SEQ_BPM: u32 = 1000;
+
+/// Lowest pitch left by [`SayCommands::sanitize`], in semitones.
+pub const MIN_PITCH_SEMITONES: i32 = -48;
+
+/// Highest pitch left by [`SayCommands::sanitize`], in semitones.
+pub const MAX_PITCH_SEMITONES: i32 = 12;
+
+/// Lowest speed left by [`SayCommands::sanitize`], in percent.
+pub const MIN_SPEED: u32 = 10;
+
+/// Highest speed left by [`SayCommands::sanitize`], in percent.
+pub const MAX_SPEED: u32 = 1000;
+
+/// Byte range of a token in the parsed source.
+///
+/// Spans are diagnostic metadata only: they never take part in comparisons or hashing, so
+/// the same command written at different positions is still the same command (and the same
+/// cache entry).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub const fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl PartialOrd for Span {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Span {
+    fn cmp(&self, _other: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+impl Hash for Span {
+    fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// A sound name was expected but something else was found.
+    ExpectedSoundName,
+    /// A known argument prefix followed by a malformed value, e.g. `pX`.
+    BadArgument,
+    /// A token that is not an argument at all.
+    UnknownOption,
+    /// A sound that does not exist in [`SoundStorage`].
+    UnknownSound { suggestions: Vec<String> },
+    /// An `af=` argument that is not on the allowlist.
+    RejectedAudioFilter { reason: String },
+    /// A `(` without the matching `)`.
+    UnclosedGroup,
+    /// A group nested deeper than [`MAX_GROUP_DEPTH`].
+    NestedTooDeeply,
+    /// A macro that cannot be expanded.
+    Macro { name: String, reason: String },
+    /// A `$name` that was not bound earlier in the message.
+    UndefinedVariable,
+}
+
+/// Describes why (part of) a message could not be turned into playable say commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Byte range of the offending token in the source text.
+    pub span: Range<usize>,
+    pub kind: DiagnosticKind,
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic under the line of `source` it points at, e.g.
+    ///
+    /// ```text
+    /// a 120 pX
+    ///       ^^ invalid argument
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let line_start = source[..self.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[self.span.start..]
+            .find('\n')
+            .map_or(source.len(), |i| self.span.start + i);
+        let line = &source[line_start..line_end];
+        let indent = source[line_start..self.span.start].chars().count();
+        let width = source[self.span.start..self.span.end.min(line_end)]
+            .chars()
+            .count()
+            .max(1);
+        format!("{line}\n{}{} {self}", " ".repeat(indent), "^".repeat(width))
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            DiagnosticKind::ExpectedSoundName => write!(f, "expected a sound name"),
+            DiagnosticKind::BadArgument => write!(f, "invalid argument"),
+            DiagnosticKind::UnknownOption => write!(f, "unknown option"),
+            DiagnosticKind::UnknownSound { suggestions } if suggestions.is_empty() => {
+                write!(f, "unknown sound")
+            }
+            DiagnosticKind::UnknownSound { suggestions } => write!(
+                f,
+                "unknown sound; did you mean {}?",
+                suggestions
+                    .iter()
+                    .map(|s| format!("`{s}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            DiagnosticKind::RejectedAudioFilter { reason } => {
+                write!(f, "rejected audio filter: {reason}")
+            }
+            DiagnosticKind::UnclosedGroup => write!(f, "unclosed group"),
+            DiagnosticKind::NestedTooDeeply => write!(f, "groups nested too deeply"),
+            DiagnosticKind::Macro { name, reason } => write!(f, "macro `{name}` {reason}"),
+            DiagnosticKind::UndefinedVariable => write!(f, "undefined variable"),
+        }
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
 #[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Hash)]
 pub enum Action {
     Synthesize,
     Concat,
 }
 
+/// Pitch shift of a command, kept in the notation it was written in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord, Hash)]
+pub enum Pitch {
+    /// `p80`: frequency in percent of the original.
+    Percent(u32),
+    /// `p+3st` or `p+50c`: shift in cents.
+    Cents(i32),
+}
+
+impl Pitch {
+    /// Frequency of the shifted sound relative to the original.
+    pub fn ratio(self) -> f64 {
+        match self {
+            Self::Percent(percent) => percent as f64 / 100.0,
+            Self::Cents(cents) => (cents as f64 / 1200.0).exp2(),
+        }
+    }
+
+    pub fn semitones(self) -> f64 {
+        match self {
+            Self::Percent(_) => 12.0 * self.ratio().log2(),
+            Self::Cents(cents) => cents as f64 / 100.0,
+        }
+    }
+
+    /// Moves the pitch to the nearest bound if it lies outside `min..=max` semitones.
+    fn clamp_semitones(self, min: i32, max: i32) -> Self {
+        let semitones = self.semitones();
+        if semitones < min as f64 {
+            Self::Cents(min * 100)
+        } else if semitones > max as f64 {
+            Self::Cents(max * 100)
+        } else {
+            self
+        }
+    }
+}
+
+impl Default for Pitch {
+    fn default() -> Self {
+        Self::Percent(100)
+    }
+}
+
+impl From<u32> for Pitch {
+    fn from(percent: u32) -> Self {
+        Self::Percent(percent)
+    }
+}
+
+impl std::fmt::Display for Pitch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Percent(percent) => write!(f, "{percent}"),
+            Self::Cents(cents) if cents % 100 == 0 => write!(f, "{:+}st", cents / 100),
+            Self::Cents(cents) => write!(f, "{cents:+}c"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Hash, Builder)]
 #[builder(default)]
 pub struct SayCommand {
     pub name: String,
     pub speed: u32,
-    pub pitch: u32,
+    #[builder(setter(into))]
+    pub pitch: Pitch,
+    /// Loudness in percent, applied to the track rather than to the decoded data.
+    pub volume: u32,
     pub wait: u32,
-    pub start: u32,
+    /// Where to start in the sound in milliseconds. Negative values count from its end.
+    pub start: i32,
+    /// How much of the sound to play in milliseconds, or everything up to its end if `None`.
     pub duration: Option<u32>,
     pub stop: bool,
+    /// Plays the sound backwards.
+    pub reverse: bool,
+    /// Length of the fade-in in milliseconds of playback.
+    pub fade_in: u32,
+    /// Length of the fade-out in milliseconds of playback.
+    pub fade_out: u32,
+    /// Stereo position from -100 (left) to 100 (right). Anything but 0 plays in stereo.
+    pub pan: i32,
+    /// Fixed start in milliseconds from the start of the message, regardless of the commands
+    /// before it. The commands after it follow it as usual.
+    pub at: Option<u32>,
     pub action: Action,
-    pub audio_filter: Option<String>,
+    pub audio_filter: Option<AudioFilter>,
+    /// Where the sound name appears in the parsed source, if it was parsed.
+    pub span: Span,
 }
 
 impl Default for SayCommand {
@@ -37,198 +283,1281 @@ impl Default for SayCommand {
         Self {
             name: "".into(),
             speed: 100,
-            pitch: 100,
+            pitch: Pitch::default(),
+            volume: 100,
             wait: 0,
             start: 0,
             duration: None,
             stop: false,
+            reverse: false,
+            fade_in: 0,
+            fade_out: 0,
+            pan: 0,
+            at: None,
             action: Action::Synthesize,
             audio_filter: None,
+            span: Span::default(),
         }
     }
 }
 
-impl std::fmt::Display for SayCommand {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.name)?;
+impl SayCommand {
+    /// Resolves [`SayCommand::start`] against a sound that lasts `sound_duration`.
+    pub fn start_offset(&self, sound_duration: Duration) -> Duration {
+        let start = Duration::from_millis(self.start.unsigned_abs() as u64);
+        if self.start < 0 {
+            sound_duration.saturating_sub(start)
+        } else {
+            start
+        }
+    }
+
+    /// Narrows the command down to the part of `file` covered by `marker`. The start and
+    /// duration of the command are taken relative to the marker.
+    fn apply_marker(&mut self, file: &SoundFile, marker: Marker) {
+        let end = marker
+            .duration
+            .map_or(file.duration(), |duration| marker.start + duration)
+            .min(file.duration());
+        let length = end.saturating_sub(marker.start);
+        let start = self.start_offset(length).min(length);
+        let remaining = length - start;
+        let duration = self.duration.map_or(remaining, |duration| {
+            Duration::from_millis(duration as u64).min(remaining)
+        });
+        self.name = file.name.clone();
+        self.start = (marker.start + start).as_millis() as i32;
+        self.duration = Some(duration.as_millis() as u32);
+    }
+
+    fn apply(&mut self, arg: SayArg) {
+        match arg {
+            SayArg::At(n) => self.at = Some(n),
+            SayArg::Speed(n) => self.speed = n,
+            SayArg::Pitch(n) => self.pitch = n,
+            SayArg::Volume(n) => self.volume = n,
+            SayArg::Wait(n) => self.wait = n,
+            SayArg::Start(n) => self.start = n,
+            SayArg::Duration(n) => self.duration = n,
+            SayArg::Stop => self.stop = true,
+            SayArg::Reverse => self.reverse = true,
+            SayArg::FadeIn(n) => self.fade_in = n,
+            SayArg::FadeOut(n) => self.fade_out = n,
+            SayArg::Pan(n) => self.pan = n,
+            SayArg::AudioFilter(af) => self.audio_filter = Some(af),
+            // Repetition applies to nodes rather than commands.
+            SayArg::Repeat(_) => {}
+        }
+    }
+
+    /// Writes the arguments that differ from their defaults, each preceded by a space.
+    fn fmt_args(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(at) = self.at {
+            write!(f, " @t{}", Seconds(at.into()))?;
+        }
         if self.speed != 100 {
             write!(f, " {}", self.speed)?;
         }
-        if self.pitch != 100 {
+        if self.pitch != Pitch::default() {
             write!(f, " p{}", self.pitch)?;
         }
+        if self.volume != 100 {
+            write!(f, " v{}", self.volume)?;
+        }
         if self.wait != 0 {
-            write!(f, " w{:.1}", (self.wait as f64) / 1000.0)?;
+            write!(f, " w{}", Seconds(self.wait.into()))?;
         }
         if self.start != 0 {
-            write!(f, " s{:.1}", (self.start as f64) / 1000.0)?;
+            write!(f, " s{}", Seconds(self.start.into()))?;
         }
         if let Some(dur) = self.duration {
-            write!(f, " d{:.1}", (dur as f64) / 1000.0)?;
+            write!(f, " d{}", Seconds(dur.into()))?;
         }
         if self.stop {
             write!(f, " s")?;
         }
+        if self.reverse {
+            write!(f, " rev")?;
+        }
+        if self.fade_in != 0 {
+            write!(f, " fi{}", Seconds(self.fade_in.into()))?;
+        }
+        if self.fade_out != 0 {
+            write!(f, " fo{}", Seconds(self.fade_out.into()))?;
+        }
+        match self.pan.cmp(&0) {
+            Ordering::Less => write!(f, " L{}", self.pan.unsigned_abs())?,
+            Ordering::Greater => write!(f, " R{}", self.pan)?,
+            Ordering::Equal => {}
+        }
         if let Some(ref af) = self.audio_filter {
             write!(f, " af={af}")?;
         }
-        match self.action {
-            Action::Synthesize => write!(f, "; "),
-            Action::Concat => write!(f, "| "),
+        Ok(())
+    }
+}
+
+/// Milliseconds written as seconds with as many decimals as needed, such as `1.25` or `-0.5`.
+struct Seconds(i64);
+
+impl std::fmt::Display for Seconds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let millis = self.0.unsigned_abs();
+        write!(f, "{sign}{}", millis / 1000)?;
+        match millis % 1000 {
+            0 => Ok(()),
+            frac => write!(f, ".{}", format!("{frac:03}").trim_end_matches('0')),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct SayCommands(Vec<SayCommand>);
+impl std::fmt::Display for SayCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        self.fmt_args(f)
+    }
+}
+
+/// Sequences that start at the same instant, written `(a & b; c & d)`.
+///
+/// The group lasts as long as its longest lane.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Layer {
+    pub lanes: Vec<SayCommands>,
+    pub action: Action,
+}
+
+impl std::fmt::Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lanes: Vec<_> = self.lanes.iter().map(|lane| lane.to_string()).collect();
+        write!(f, "({})", lanes.join(" & "))
+    }
+}
+
+/// A command or group played `count` times in a row, written `a*4` or `(a | b)*3`.
+///
+/// Every repetition is followed by the action of `node`, so `a*3| b` is `a| a| a| b`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Repeat {
+    pub node: Box<SayNode>,
+    pub count: u32,
+}
+
+impl std::fmt::Display for Repeat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // An audio filter runs up to the next space, so it must not swallow the count.
+        let space = if self.node.ends_with_audio_filter() {
+            " "
+        } else {
+            ""
+        };
+        write!(f, "{}{space}*{}", self.node, self.count)
+    }
+}
+
+/// The sounds a [`Choice`] picks from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Candidates {
+    /// One of the listed sounds, written `{a,b,c}`.
+    Sounds(Vec<String>),
+    /// Any sound in [`SoundStorage`], written `{*}`.
+    Any,
+}
+
+impl std::fmt::Display for Candidates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sounds(names) => write!(f, "{{{}}}", names.join(",")),
+            Self::Any => write!(f, "{{*}}"),
+        }
+    }
+}
+
+/// A command whose sound is picked at play time, written `{a,b,c} 120`.
+///
+/// The name of `command` is left empty until the choice is resolved with
+/// [`SayCommands::resolve_choices`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Choice {
+    pub candidates: Candidates,
+    pub command: SayCommand,
+}
+
+impl std::fmt::Display for Choice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.candidates, self.command)
+    }
+}
+
+/// A step sequencer, written `seq 140bpm kick:x...x... snare:....x... v80`.
+///
+/// Every step is a sixteenth note. The sequence lasts as long as its longest pattern; with `|`
+/// the next node also waits for the last sound to end.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Seq {
+    pub bpm: u32,
+    pub tracks: Vec<SeqTrack>,
+    pub action: Action,
+}
+
+impl Seq {
+    pub fn step_duration(&self) -> Duration {
+        Duration::from_secs(60) / (self.bpm * 4)
+    }
+
+    /// Number of steps in the longest pattern.
+    pub fn len(&self) -> usize {
+        self.tracks
+            .iter()
+            .map(|track| track.steps.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn hit_count(&self) -> usize {
+        self.tracks
+            .iter()
+            .map(|track| track.steps.iter().filter(|hit| **hit).count())
+            .sum()
+    }
+}
+
+impl std::fmt::Display for Seq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "seq {}bpm", self.bpm)?;
+        for track in &self.tracks {
+            write!(f, " {track}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An instrument of a [`Seq`]: the command played on every `x` of `steps`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SeqTrack {
+    pub command: SayCommand,
+    pub steps: Vec<bool>,
+}
+
+impl std::fmt::Display for SeqTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.command.name)?;
+        for hit in &self.steps {
+            write!(f, "{}", if *hit { 'x' } else { '.' })?;
+        }
+        self.command.fmt_args(f)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SayNode {
+    Command(SayCommand),
+    Layer(Layer),
+    Repeat(Repeat),
+    Choice(Choice),
+    Seq(Seq),
+}
+
+impl SayNode {
+    pub fn action(&self) -> &Action {
+        match self {
+            Self::Command(cmd) => &cmd.action,
+            Self::Layer(layer) => &layer.action,
+            Self::Repeat(repeat) => repeat.node.action(),
+            Self::Choice(choice) => &choice.command.action,
+            Self::Seq(seq) => &seq.action,
+        }
+    }
+
+    fn action_mut(&mut self) -> &mut Action {
+        match self {
+            Self::Command(cmd) => &mut cmd.action,
+            Self::Layer(layer) => &mut layer.action,
+            Self::Repeat(repeat) => repeat.node.action_mut(),
+            Self::Choice(choice) => &mut choice.command.action,
+            Self::Seq(seq) => &mut seq.action,
+        }
+    }
+
+    /// Whether the written form ends with an `af=` argument, which runs up to the next space.
+    fn ends_with_audio_filter(&self) -> bool {
+        match self {
+            Self::Command(cmd) => cmd.audio_filter.is_some(),
+            Self::Choice(choice) => choice.command.audio_filter.is_some(),
+            Self::Seq(seq) => seq
+                .tracks
+                .last()
+                .is_some_and(|track| track.command.audio_filter.is_some()),
+            Self::Layer(_) | Self::Repeat(_) => false,
+        }
+    }
+
+    /// Returns the commands written in this node in source order. Repeated commands and
+    /// sequencer tracks are returned once and unresolved choices are skipped.
+    pub fn commands(&self) -> Vec<&SayCommand> {
+        match self {
+            Self::Command(cmd) => vec![cmd],
+            Self::Layer(layer) => layer.lanes.iter().flat_map(SayCommands::iter).collect(),
+            Self::Repeat(repeat) => repeat.node.commands(),
+            Self::Choice(_) => vec![],
+            Self::Seq(seq) => seq.tracks.iter().map(|track| &track.command).collect(),
+        }
+    }
+
+    fn into_commands(self) -> Vec<SayCommand> {
+        match self {
+            Self::Command(cmd) => vec![cmd],
+            Self::Layer(layer) => layer.lanes.into_iter().flatten().collect(),
+            Self::Repeat(repeat) => repeat.node.into_commands(),
+            Self::Choice(_) => vec![],
+            Self::Seq(seq) => seq.tracks.into_iter().map(|track| track.command).collect(),
+        }
+    }
+
+    fn commands_mut(&mut self) -> Vec<&mut SayCommand> {
+        match self {
+            Self::Command(cmd) | Self::Choice(Choice { command: cmd, .. }) => vec![cmd],
+            Self::Layer(layer) => layer
+                .lanes
+                .iter_mut()
+                .flat_map(SayCommands::commands_mut)
+                .collect(),
+            Self::Repeat(repeat) => repeat.node.commands_mut(),
+            Self::Seq(seq) => seq
+                .tracks
+                .iter_mut()
+                .map(|track| &mut track.command)
+                .collect(),
+        }
+    }
+
+    /// Number of groups nested in this node, counting the node itself.
+    pub fn depth(&self) -> usize {
+        match self {
+            Self::Layer(layer) => {
+                1 + layer
+                    .lanes
+                    .iter()
+                    .map(SayCommands::depth)
+                    .max()
+                    .unwrap_or(0)
+            }
+            Self::Repeat(repeat) => repeat.node.depth(),
+            Self::Command(_) | Self::Choice(_) | Self::Seq(_) => 0,
+        }
+    }
+
+    /// Number of commands played by this node once repetitions are expanded. Every repetition
+    /// counts as at least one, so that repeating something that plays nothing is bounded as well.
+    pub fn expanded_count(&self) -> usize {
+        match self {
+            Self::Command(_) | Self::Choice(_) => 1,
+            Self::Layer(layer) => layer
+                .lanes
+                .iter()
+                .map(SayCommands::expanded_count)
+                .fold(0, usize::saturating_add),
+            Self::Repeat(repeat) => repeat
+                .node
+                .expanded_count()
+                .max(1)
+                .saturating_mul(repeat.count as usize),
+            Self::Seq(seq) => seq.hit_count(),
+        }
+    }
+
+    /// Drops whatever does not fit into `budget` expanded commands and returns whether anything
+    /// of this node is left.
+    fn truncate(&mut self, budget: &mut usize) -> bool {
+        match self {
+            Self::Command(_) | Self::Choice(_) => {
+                if *budget == 0 {
+                    return false;
+                }
+                *budget -= 1;
+                true
+            }
+            Self::Layer(layer) => {
+                layer.lanes.retain_mut(|lane| lane.truncate(budget));
+                !layer.lanes.is_empty()
+            }
+            Self::Repeat(repeat) if repeat.node.expanded_count().max(1) > *budget => {
+                // Not even a single repetition fits, so keep whatever part of it does.
+                *self = *repeat.node.clone();
+                self.truncate(budget)
+            }
+            Self::Repeat(repeat) => {
+                let once = repeat.node.expanded_count().max(1);
+                repeat.count = (repeat.count as usize).min(*budget / once) as u32;
+                *budget -= repeat.count as usize * once;
+                if repeat.count == 1 {
+                    *self = *repeat.node.clone();
+                }
+                true
+            }
+            Self::Seq(seq) => {
+                // Drop the latest hits first.
+                for step in 0..seq.len() {
+                    for track in &mut seq.tracks {
+                        if let Some(hit @ true) = track.steps.get_mut(step) {
+                            if *budget == 0 {
+                                *hit = false;
+                            } else {
+                                *budget -= 1;
+                            }
+                        }
+                    }
+                }
+                seq.hit_count() > 0
+            }
+        }
+    }
+
+    fn expand_macros(
+        &mut self,
+        lookup: &impl Fn(&str) -> Option<String>,
+        stack: &mut Vec<String>,
+        budget: &mut usize,
+    ) -> Result<(), Diagnostic> {
+        match self {
+            Self::Command(cmd) => {
+                let Some(body) = lookup(&cmd.name) else {
+                    return Ok(());
+                };
+                let name = lookup_key(&cmd.name);
+                let error = |reason: &str| Diagnostic {
+                    span: cmd.span.range(),
+                    kind: DiagnosticKind::Macro {
+                        name: name.clone(),
+                        reason: reason.to_owned(),
+                    },
+                };
+                if stack.contains(&name) {
+                    return Err(error("expands to itself"));
+                }
+                if *budget == 0 {
+                    return Err(error("expands to too many macros"));
+                }
+                *budget -= 1;
+                let invocation = SayCommand {
+                    name: cmd.name.clone(),
+                    action: cmd.action.clone(),
+                    ..Default::default()
+                };
+                if *cmd != invocation {
+                    return Err(error("takes no arguments"));
+                }
+
+                let mut expanded =
+                    SayCommands::from_str(&body).map_err(|_| error("has an invalid body"))?;
+                stack.push(name.clone());
+                // Errors inside the body are reported at the invocation in the source.
+                expanded
+                    .expand_macros_with(lookup, stack, budget)
+                    .map_err(|e| Diagnostic {
+                        span: cmd.span.range(),
+                        ..e
+                    })?;
+                stack.pop();
+                // The expansion becomes a group of its own.
+                if expanded.depth() >= MAX_GROUP_DEPTH {
+                    return Err(error("nests groups too deeply"));
+                }
+                for inner in expanded.commands_mut() {
+                    inner.span = cmd.span;
+                }
+                *self = Self::Layer(Layer {
+                    lanes: vec![expanded],
+                    action: cmd.action.clone(),
+                });
+                Ok(())
+            }
+            Self::Layer(layer) => {
+                for lane in &mut layer.lanes {
+                    lane.expand_macros_with(lookup, stack, budget)?;
+                }
+                Ok(())
+            }
+            Self::Repeat(repeat) => repeat.node.expand_macros(lookup, stack, budget),
+            Self::Choice(_) | Self::Seq(_) => Ok(()),
+        }
+    }
+
+    fn mentions(&self, known: &impl Fn(&str) -> bool) -> bool {
+        match self {
+            Self::Command(cmd) => known(&cmd.name),
+            Self::Choice(choice) => match &choice.candidates {
+                Candidates::Sounds(names) => names.iter().any(|name| known(name)),
+                Candidates::Any => true,
+            },
+            Self::Layer(layer) => layer.lanes.iter().any(|lane| lane.mentions(known)),
+            Self::Repeat(repeat) => repeat.node.mentions(known),
+            Self::Seq(seq) => seq.tracks.iter().any(|track| known(&track.command.name)),
+        }
+    }
+
+    fn resolve_choices(&mut self, storage: &SoundStorage, rng: &mut StdRng) {
+        match self {
+            Self::Command(_) | Self::Seq(_) => {}
+            Self::Layer(layer) => {
+                for lane in &mut layer.lanes {
+                    lane.resolve_choices_with(storage, rng);
+                }
+            }
+            Self::Repeat(repeat) => repeat.node.resolve_choices(storage, rng),
+            Self::Choice(choice) => {
+                let name = match &choice.candidates {
+                    Candidates::Sounds(names) => names.choose(rng).cloned(),
+                    Candidates::Any => storage.get_random_with(rng).map(|file| file.name),
+                };
+                *self = Self::Command(SayCommand {
+                    name: name.unwrap_or_default(),
+                    ..choice.command.clone()
+                });
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for SayNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Command(cmd) => write!(f, "{cmd}"),
+            Self::Layer(layer) => write!(f, "{layer}"),
+            Self::Repeat(repeat) => write!(f, "{repeat}"),
+            Self::Choice(choice) => write!(f, "{choice}"),
+            Self::Seq(seq) => write!(f, "{seq}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SayCommands(Vec<SayNode>);
 
 impl SayCommands {
-    pub fn iter(&self) -> std::slice::Iter<'_, SayCommand> {
+    pub fn nodes(&self) -> std::slice::Iter<'_, SayNode> {
         self.0.iter()
     }
 
+    /// Iterates over every command in source order, including those inside groups. Repeated
+    /// commands are yielded once.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            stack: vec![self.0.iter()],
+            tracks: std::slice::Iter::default(),
+        }
+    }
+
+    fn commands_mut(&mut self) -> Vec<&mut SayCommand> {
+        self.0.iter_mut().flat_map(SayNode::commands_mut).collect()
+    }
+
     pub const fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 
+    /// Number of groups nested in one another.
+    pub fn depth(&self) -> usize {
+        self.0.iter().map(SayNode::depth).max().unwrap_or(0)
+    }
+
+    /// Number of commands played once repetitions are expanded.
+    pub fn expanded_count(&self) -> usize {
+        self.0
+            .iter()
+            .map(SayNode::expanded_count)
+            .fold(0, usize::saturating_add)
+    }
+
+    fn truncate(&mut self, budget: &mut usize) -> bool {
+        self.0.retain_mut(|node| node.truncate(budget));
+        !self.0.is_empty()
+    }
+
+    /// Clamps arguments to their playable ranges and drops everything after the first
+    /// [`MAX_EXPANDED_COMMANDS`] expanded commands.
     pub fn sanitize(&mut self) {
-        for cmd in self.0.iter_mut() {
-            cmd.pitch = std::cmp::max(cmd.pitch, 1);
-            cmd.pitch = std::cmp::min(cmd.pitch, 200);
+        for cmd in self.commands_mut() {
+            cmd.pitch = cmd
+                .pitch
+                .clamp_semitones(MIN_PITCH_SEMITONES, MAX_PITCH_SEMITONES);
+            cmd.volume = std::cmp::min(cmd.volume, 200);
+            cmd.speed = cmd.speed.clamp(MIN_SPEED, MAX_SPEED);
+        }
+        let mut budget = MAX_EXPANDED_COMMANDS;
+        self.truncate(&mut budget);
+    }
+
+    /// Replaces every command naming a macro with a group of the macro's body, as returned by
+    /// `lookup`. Macros used in the body are expanded as well; candidates of a choice are not.
+    ///
+    /// The inserted commands take the span of the invocation so that diagnostics point at it.
+    pub fn expand_macros(
+        &mut self,
+        lookup: &impl Fn(&str) -> Option<String>,
+    ) -> Result<(), Diagnostic> {
+        let mut budget = MAX_MACRO_EXPANSIONS;
+        self.expand_macros_with(lookup, &mut Vec::new(), &mut budget)
+    }
+
+    fn expand_macros_with(
+        &mut self,
+        lookup: &impl Fn(&str) -> Option<String>,
+        stack: &mut Vec<String>,
+        budget: &mut usize,
+    ) -> Result<(), Diagnostic> {
+        for node in &mut self.0 {
+            node.expand_macros(lookup, stack, budget)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces every choice with a command playing one of its candidates. A repeated choice is
+    /// picked once for all of its repetitions.
+    ///
+    /// Passing a `seed` makes the picks reproducible.
+    pub fn resolve_choices(&mut self, storage: &SoundStorage, seed: Option<u64>) {
+        let mut rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+        self.resolve_choices_with(storage, &mut rng);
+    }
+
+    fn resolve_choices_with(&mut self, storage: &SoundStorage, rng: &mut StdRng) {
+        for node in &mut self.0 {
+            node.resolve_choices(storage, rng);
+        }
+    }
+
+    /// Replaces every `sound#marker` with the part of the sound the marker covers. Unknown
+    /// markers are left as they are.
+    pub fn resolve_markers(&mut self, storage: &SoundStorage) {
+        for cmd in self.commands_mut() {
+            if !cmd.name.contains('#') {
+                continue;
+            }
+            if let Some((file, Some(marker))) = storage.get_marked(&cmd.name) {
+                cmd.apply_marker(&file, marker);
+            }
         }
     }
+
+    /// Returns whether any command, sequencer track or candidate of a choice has a name `known`
+    /// accepts. `{*}` picks from every sound, so it always counts.
+    pub fn mentions(&self, known: &impl Fn(&str) -> bool) -> bool {
+        self.0.iter().any(|node| node.mentions(known))
+    }
+
+    /// Reports every command whose sound (or marker) does not exist in `storage`, together with
+    /// the closest existing names.
+    pub fn unknown_sounds(&self, storage: &SoundStorage) -> Vec<Diagnostic> {
+        self.iter()
+            .filter(|cmd| {
+                Synth::from_name(&cmd.name).is_none() && storage.get_marked(&cmd.name).is_none()
+            })
+            .map(|cmd| Diagnostic {
+                span: cmd.span.range(),
+                kind: DiagnosticKind::UnknownSound {
+                    suggestions: storage
+                        .calc_similarities(&cmd.name)
+                        .into_iter()
+                        .take(MAX_SUGGESTIONS)
+                        .map(|(_, file)| file.name)
+                        .collect(),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Iterator over the commands of [`SayCommands`], returned by [`SayCommands::iter`].
+pub struct Iter<'a> {
+    /// Sequences being visited, innermost last.
+    stack: Vec<std::slice::Iter<'a, SayNode>>,
+    /// Remaining tracks of the sequencer being visited.
+    tracks: std::slice::Iter<'a, SeqTrack>,
 }
 
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a SayCommand;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(track) = self.tracks.next() {
+                return Some(&track.command);
+            }
+            let Some(node) = self.stack.last_mut()?.next() else {
+                self.stack.pop();
+                continue;
+            };
+            match node {
+                SayNode::Command(cmd) => return Some(cmd),
+                SayNode::Choice(_) => {}
+                SayNode::Layer(layer) => self
+                    .stack
+                    .extend(layer.lanes.iter().rev().map(|lane| lane.0.iter())),
+                SayNode::Repeat(repeat) => self
+                    .stack
+                    .push(std::slice::from_ref(repeat.node.as_ref()).iter()),
+                SayNode::Seq(seq) => self.tracks = seq.tracks.iter(),
+            }
+        }
+    }
+}
+
+/// Yields every command in source order, as [`SayCommands::iter`] does.
 impl IntoIterator for SayCommands {
     type IntoIter = std::vec::IntoIter<Self::Item>;
     type Item = SayCommand;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.0
+            .into_iter()
+            .flat_map(SayNode::into_commands)
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 }
 
 impl FromStr for SayCommands {
-    type Err = Error<String>;
+    type Err = Diagnostic;
 
     #[tracing::instrument(skip_all)]
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match say_commands(s).finish() {
-            Ok((_remaining, commands)) => Ok(Self(commands)),
-            Err(Error { input, code }) => Err(Error {
-                input: input.to_string(),
-                code,
-            }),
+        // Bindings alone leave nothing to play, and that prints as an empty string.
+        if s.trim().is_empty() {
+            return Ok(Self(Vec::new()));
         }
+        let parser = Parser {
+            source: s,
+            bindings: RefCell::default(),
+            depth: Cell::default(),
+        };
+        parser.say_commands(s, false).map(|(_, nodes)| Self(nodes))
     }
 }
 
+/// Writes the canonical form of the commands, which parses back to the same commands.
 impl std::fmt::Display for SayCommands {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut s: String = self.iter().map(|c| c.to_string()).collect();
-        s.truncate(s.len() - 2);
-        write!(f, "{s}")
+        for (i, node) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{node}")?;
+            // `;` is implied after the last node, but `|` changes how long a lane lasts.
+            match node.action() {
+                Action::Synthesize if i + 1 < self.0.len() => write!(f, ";")?,
+                Action::Synthesize => {}
+                Action::Concat if node.ends_with_audio_filter() => write!(f, " |")?,
+                Action::Concat => write!(f, "|")?,
+            }
+        }
+        Ok(())
     }
 }
 
 impl From<Vec<SayCommand>> for SayCommands {
     fn from(value: Vec<SayCommand>) -> Self {
+        Self(value.into_iter().map(SayNode::Command).collect())
+    }
+}
+
+impl From<Vec<SayNode>> for SayCommands {
+    fn from(value: Vec<SayNode>) -> Self {
         Self(value)
     }
 }
 
 enum SayArg {
+    At(u32),
     Speed(u32),
-    Pitch(u32),
+    Pitch(Pitch),
+    Volume(u32),
     Wait(u32),
-    Start(u32),
-    Duration(u32),
+    Start(i32),
+    Duration(Option<u32>),
     Stop,
-    AudioFilter(String),
+    Reverse,
+    FadeIn(u32),
+    FadeOut(u32),
+    Pan(i32),
+    AudioFilter(AudioFilter),
+    Repeat(u32),
+}
+
+fn at(i: &str) -> IResult<&str, f64> {
+    ws(preceded(tag("@t"), seconds))(i)
 }
 
 fn speed(i: &str) -> IResult<&str, u32> {
     ws(preceded(opt(char('@')), u32))(i)
 }
 
-fn pitch(i: &str) -> IResult<&str, u32> {
-    ws(preceded(char('p'), u32))(i)
-}
+fn pitch(i: &str) -> IResult<&str, Pitch> {
+    ws(preceded(
+        char('p'),
+        alt((
+            map_opt(terminated(i32, tag("st")), |n| {
+                n.checked_mul(100).map(Pitch::Cents)
+            }),
+            map(terminated(i32, char('c')), Pitch::Cents),
+            map(u32, Pitch::Percent),
+        )),
+    ))(i)
+}
+
+fn volume(i: &str) -> IResult<&str, u32> {
+    ws(preceded(char('v'), u32))(i)
+}
+
+/// Parses seconds, either plain (`83.4`) or with minutes (`1:23.4`).
+fn seconds(i: &str) -> IResult<&str, f64> {
+    alt((
+        map(
+            pair(
+                opt(char('-')),
+                separated_pair(u32, char(':'), verify(double, |s| *s >= 0.0)),
+            ),
+            |(sign, (minutes, seconds))| {
+                let seconds = minutes as f64 * 60.0 + seconds;
+                if sign.is_some() { -seconds } else { seconds }
+            },
+        ),
+        double,
+    ))(i)
+}
+
+fn wait(i: &str) -> IResult<&str, f64> {
+    ws(preceded(char('w'), seconds))(i)
+}
+
+fn start(i: &str) -> IResult<&str, f64> {
+    ws(preceded(char('s'), seconds))(i)
+}
+
+/// Parses a duration in seconds, or `None` for `dEnd`.
+fn duration(i: &str) -> IResult<&str, Option<f64>> {
+    ws(preceded(
+        char('d'),
+        alt((map(tag_no_case("end"), |_| None), map(seconds, Some))),
+    ))(i)
+}
+
+fn stop(i: &str) -> IResult<&str, &str> {
+    ws(alt((tag("stop"), tag("s"))))(i)
+}
+
+fn reverse(i: &str) -> IResult<&str, &str> {
+    ws(tag("rev"))(i)
+}
+
+fn fade_in(i: &str) -> IResult<&str, f64> {
+    ws(preceded(tag("fi"), double))(i)
+}
+
+fn fade_out(i: &str) -> IResult<&str, f64> {
+    ws(preceded(tag("fo"), double))(i)
+}
+
+fn pan(i: &str) -> IResult<&str, i32> {
+    ws(alt((
+        preceded(char('L'), map(u32, |n| -(n.min(100) as i32))),
+        preceded(char('R'), map(u32, |n| n.min(100) as i32)),
+    )))(i)
+}
+
+fn repeat(i: &str) -> IResult<&str, u32> {
+    ws(preceded(char('*'), verify(u32, |n| *n > 0)))(i)
+}
+
+/// Parses the separator after a command or group. The end of a lane (`&` or `)`) is left for
+/// the enclosing group to consume.
+fn action(i: &str) -> IResult<&str, &str> {
+    ws(alt((
+        tag(";"),
+        tag("|"),
+        eof,
+        peek(tag("&")),
+        peek(tag(")")),
+    )))(i)
+}
+
+fn open_group(i: &str) -> IResult<&str, &str> {
+    ws(tag("("))(i)
+}
+
+fn lane_delimiter(i: &str) -> IResult<&str, char> {
+    ws(one_of("&)"))(i)
+}
+
+fn audio_filter(i: &str) -> IResult<&str, &str> {
+    ws(preceded(
+        tag("af="),
+        take_till(|c| c == ';' || c == ' ' || c == '&' || c == ')'),
+    ))(i)
+}
+
+fn say_arg(input: &str) -> IResult<&str, SayArg> {
+    alt((
+        map(at, |n| SayArg::At((n * 1000.0).round() as u32)),
+        map(speed, SayArg::Speed),
+        map(pitch, SayArg::Pitch),
+        map(volume, SayArg::Volume),
+        map(wait, |n| SayArg::Wait((n * 1000.0).round() as u32)),
+        map(start, |n| SayArg::Start((n * 1000.0).round() as i32)),
+        map(duration, |n| {
+            SayArg::Duration(n.map(|n| (n * 1000.0).round() as u32))
+        }),
+        map(stop, |_| SayArg::Stop),
+        map(reverse, |_| SayArg::Reverse),
+        map(fade_in, |n| SayArg::FadeIn((n * 1000.0).round() as u32)),
+        map(fade_out, |n| SayArg::FadeOut((n * 1000.0).round() as u32)),
+        map(pan, SayArg::Pan),
+        map(
+            map_res(audio_filter, AudioFilter::from_str),
+            SayArg::AudioFilter,
+        ),
+        map(repeat, SayArg::Repeat),
+    ))(input)
+}
+
+/// Parses a sound name, which may be written in any script and in full-width characters.
+/// [`SoundStorage`] normalizes it when looking the sound up.
+fn sound_name(input: &str) -> IResult<&str, &str> {
+    ws(take_while1(|c: char| {
+        c.is_alphanumeric() || "-_^!.#－＿＾！．＃".contains(c)
+    }))(input)
+}
+
+/// Parses `{a,b,c}` or `{*}`, also returning the consumed token.
+fn choice(input: &str) -> IResult<&str, (&str, Candidates)> {
+    ws(consumed(delimited(
+        char('{'),
+        alt((
+            map(ws(char('*')), |_| Candidates::Any),
+            map(separated_list1(char(','), sound_name), |names| {
+                Candidates::Sounds(names.into_iter().map(str::to_owned).collect())
+            }),
+        )),
+        char('}'),
+    )))(input)
+}
+
+/// Parses `seq 140bpm`, returning the tempo.
+fn seq_tempo(input: &str) -> IResult<&str, u32> {
+    preceded(
+        delimited(multispace0, tag("seq"), multispace1),
+        ws(terminated(
+            verify(u32, |bpm| (1..=MAX_SEQ_BPM).contains(bpm)),
+            tag_no_case("bpm"),
+        )),
+    )(input)
+}
+
+/// Parses `kick:x...x...`, returning the instrument and its steps.
+fn seq_track(input: &str) -> IResult<&str, (&str, Vec<bool>)> {
+    ws(separated_pair(
+        sound_name,
+        char(':'),
+        map(take_while1(|c| c == 'x' || c == '.'), |steps: &str| {
+            steps.chars().map(|c| c == 'x').collect()
+        }),
+    ))(input)
+}
+
+/// Parses `$name`, returning it including the `$`.
+fn variable(input: &str) -> IResult<&str, &str> {
+    ws(recognize(preceded(
+        char('$'),
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    )))(input)
+}
+
+fn assign(input: &str) -> IResult<&str, char> {
+    ws(char('='))(input)
+}
+
+/// Returns the first sound name of `s`, regardless of whether the rest of it parses.
+pub fn leading_sound_name(s: &str) -> Option<&str> {
+    sound_name(s).ok().map(|(_, name)| name)
+}
+
+/// Runs the grammar over a single message, keeping the whole source around so that spans and
+/// diagnostics can point back into it.
+struct Parser<'a> {
+    source: &'a str,
+    /// Nodes bound by `$name = ...` so far.
+    bindings: RefCell<HashMap<&'a str, SayNode>>,
+    /// Number of groups enclosing the input being parsed.
+    depth: Cell<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn span(&self, token: &str) -> Span {
+        let start = self.source.offset(token);
+        Span {
+            start,
+            end: start + token.len(),
+        }
+    }
+
+    /// Builds a diagnostic for the token at the beginning of `rest`.
+    fn diagnostic(&self, rest: &'a str, kind: DiagnosticKind) -> Diagnostic {
+        let len = match rest.find(|c: char| c.is_whitespace() || "|;&()".contains(c)) {
+            Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+            Some(n) => n,
+            None => rest.len(),
+        };
+        Diagnostic {
+            span: self.span(&rest[..len]).range(),
+            kind,
+        }
+    }
+
+    /// Builds a diagnostic for a token that could not be read as an argument.
+    fn unexpected(&self, rest: &'a str) -> Diagnostic {
+        // `af=` values may contain `|`, so their extent is determined by the `af=` parser itself.
+        if let Ok((_, af)) = audio_filter(rest)
+            && let Err(e) = AudioFilter::from_str(af)
+        {
+            return Diagnostic {
+                span: self.source.offset(rest)..self.source.offset(af) + af.len(),
+                kind: DiagnosticKind::RejectedAudioFilter {
+                    reason: e.to_string(),
+                },
+            };
+        }
+        self.diagnostic(rest, classify_unexpected(rest))
+    }
+
+    fn say_command(&self, input: &'a str) -> Result<(&'a str, SayNode), Diagnostic> {
+        let (input, (name, candidates)) = alt((
+            map(choice, |(token, candidates)| (token, Some(candidates))),
+            map(sound_name, |name| (name, None)),
+        ))(input)
+        .map_err(|e| self.diagnostic(failed_at(e), DiagnosticKind::ExpectedSoundName))?;
+        let (input, opts) = many0(say_arg)(input)
+            .map_err(|e| self.diagnostic(failed_at(e), DiagnosticKind::BadArgument))?;
+        let (input, action) = self.action(input)?;
 
-fn wait(i: &str) -> IResult<&str, f64> {
-    ws(preceded(char('w'), double))(i)
-}
+        let mut saycmd = SayCommand {
+            name: if candidates.is_some() {
+                String::new()
+            } else {
+                name.to_string()
+            },
+            action,
+            span: self.span(name),
+            ..Default::default()
+        };
 
-fn start(i: &str) -> IResult<&str, f64> {
-    ws(preceded(char('s'), double))(i)
-}
+        let mut counts = Vec::new();
+        for opt in opts {
+            match opt {
+                SayArg::Repeat(n) => counts.push(n),
+                opt => saycmd.apply(opt),
+            }
+        }
 
-fn duration(i: &str) -> IResult<&str, f64> {
-    ws(preceded(char('d'), double))(i)
-}
+        let node = match candidates {
+            Some(candidates) => SayNode::Choice(Choice {
+                candidates,
+                command: saycmd,
+            }),
+            None => SayNode::Command(saycmd),
+        };
+        Ok((input, counts.into_iter().fold(node, repeated)))
+    }
 
-fn stop(i: &str) -> IResult<&str, &str> {
-    ws(alt((tag("stop"), tag("s"))))(i)
-}
+    /// Parses the tracks of a sequencer whose tempo was already consumed.
+    fn seq(&self, mut input: &'a str, bpm: u32) -> Result<(&'a str, SayNode), Diagnostic> {
+        let mut tracks: Vec<SeqTrack> = Vec::new();
+        loop {
+            if let Ok((rest, (name, steps))) = seq_track(input) {
+                tracks.push(SeqTrack {
+                    command: SayCommand {
+                        name: name.to_owned(),
+                        span: self.span(name),
+                        ..Default::default()
+                    },
+                    steps,
+                });
+                input = rest;
+                continue;
+            }
+            // A trailing `*N` repeats the whole sequence.
+            if repeat(input).is_ok() {
+                break;
+            }
+            let Some(track) = tracks.last_mut() else {
+                return Err(self.diagnostic(input, DiagnosticKind::ExpectedSoundName));
+            };
+            let Ok((rest, arg)) = say_arg(input) else {
+                break;
+            };
+            track.command.apply(arg);
+            input = rest;
+        }
+        let (input, counts) = many0(repeat)(input)
+            .map_err(|e| self.diagnostic(failed_at(e), DiagnosticKind::BadArgument))?;
+        let (input, action) = self.action(input)?;
+        let seq = SayNode::Seq(Seq {
+            bpm,
+            tracks,
+            action,
+        });
+        Ok((input, counts.into_iter().fold(seq, repeated)))
+    }
 
-fn action(i: &str) -> IResult<&str, &str> {
-    ws(alt((tag(";"), tag("|"), eof)))(i)
-}
+    fn action(&self, input: &'a str) -> Result<(&'a str, Action), Diagnostic> {
+        map(action, |c| match c {
+            "|" => Action::Concat,
+            _ => Action::Synthesize,
+        })(input)
+        .map_err(|e| self.unexpected(failed_at(e)))
+    }
 
-fn audio_filter(i: &str) -> IResult<&str, &str> {
-    ws(preceded(tag("af="), take_till(|c| c == ';' || c == ' ')))(i)
-}
+    /// Parses the lanes of a group whose opening parenthesis `paren` was already consumed.
+    fn layer(&self, input: &'a str, paren: &'a str) -> Result<(&'a str, SayNode), Diagnostic> {
+        let depth = self.depth.get();
+        if depth == MAX_GROUP_DEPTH {
+            return Err(Diagnostic {
+                span: self.span(paren).range(),
+                kind: DiagnosticKind::NestedTooDeeply,
+            });
+        }
+        self.depth.set(depth + 1);
+        let result = self.lanes(input, paren);
+        self.depth.set(depth);
+        result
+    }
 
-fn say_arg(input: &str) -> IResult<&str, SayArg> {
-    alt((
-        map(speed, SayArg::Speed),
-        map(pitch, SayArg::Pitch),
-        map(wait, |n| SayArg::Wait((n * 1000.0) as u32)),
-        map(start, |n| SayArg::Start((n * 1000.0) as u32)),
-        map(duration, |n| SayArg::Duration((n * 1000.0) as u32)),
-        map(stop, |_| SayArg::Stop),
-        map(audio_filter, |af| SayArg::AudioFilter(af.to_owned())),
-    ))(input)
-}
+    fn lanes(&self, mut input: &'a str, paren: &'a str) -> Result<(&'a str, SayNode), Diagnostic> {
+        let mut lanes = Vec::new();
+        loop {
+            let (rest, lane) = self.say_commands(input, true)?;
+            // A lane of nothing but variable bindings plays nothing.
+            if !lane.is_empty() {
+                lanes.push(SayCommands(lane));
+            }
+            let (rest, delimiter) = lane_delimiter(rest).map_err(|_| Diagnostic {
+                span: self.span(paren).range(),
+                kind: DiagnosticKind::UnclosedGroup,
+            })?;
+            input = rest;
+            if delimiter == ')' {
+                break;
+            }
+        }
+        if lanes.is_empty() {
+            return Err(self.diagnostic(input, DiagnosticKind::ExpectedSoundName));
+        }
+        let (input, counts) = many0(repeat)(input)
+            .map_err(|e| self.diagnostic(failed_at(e), DiagnosticKind::BadArgument))?;
+        let (input, action) = self.action(input)?;
+        let layer = SayNode::Layer(Layer { lanes, action });
+        Ok((input, counts.into_iter().fold(layer, repeated)))
+    }
 
-fn sound_name(input: &str) -> IResult<&str, &str> {
-    ws(take_while1(|c: char| {
-        c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '^' || c == '!' || c == '.'
-    }))(input)
-}
+    /// Parses the node bound to the variable `name`. The binding itself plays nothing.
+    fn binding(&self, input: &'a str, name: &'a str) -> Result<&'a str, Diagnostic> {
+        let (input, mut node) = self.say_node(input)?;
+        // Every reference copies the node, so keep it from growing beyond what can be played.
+        let mut budget = MAX_EXPANDED_COMMANDS;
+        node.truncate(&mut budget);
+        self.bindings.borrow_mut().insert(name, node);
+        Ok(input)
+    }
 
-fn say_command(input: &str) -> IResult<&str, SayCommand> {
-    let (input, (name, opts)) = pair(sound_name, many0(say_arg))(input)?;
-    let (input, action) = map(action, |c| match c {
-        ";" => Action::Synthesize,
-        "|" => Action::Concat,
-        "" => Action::Synthesize,
-        _ => unreachable!(),
-    })(input)?;
+    /// Parses a reference to the variable `name`, which stands for a copy of its node.
+    fn reference(&self, input: &'a str, name: &'a str) -> Result<(&'a str, SayNode), Diagnostic> {
+        let mut node = self
+            .bindings
+            .borrow()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Diagnostic {
+                span: self.span(name).range(),
+                kind: DiagnosticKind::UndefinedVariable,
+            })?;
+        if self.depth.get() + node.depth() > MAX_GROUP_DEPTH {
+            return Err(Diagnostic {
+                span: self.span(name).range(),
+                kind: DiagnosticKind::NestedTooDeeply,
+            });
+        }
+        let (input, counts) = many0(repeat)(input)
+            .map_err(|e| self.diagnostic(failed_at(e), DiagnosticKind::BadArgument))?;
+        let (input, action) = self.action(input)?;
+        *node.action_mut() = action;
+        Ok((input, counts.into_iter().fold(node, repeated)))
+    }
 
-    let mut saycmd = SayCommand {
-        name: name.to_string(),
-        action,
-        ..Default::default()
-    };
+    fn say_node(&self, input: &'a str) -> Result<(&'a str, SayNode), Diagnostic> {
+        if let Ok((rest, paren)) = open_group(input) {
+            self.layer(rest, paren)
+        } else if let Ok((rest, name)) = variable(input) {
+            self.reference(rest, name)
+        } else if let Ok((rest, bpm)) = seq_tempo(input) {
+            self.seq(rest, bpm)
+        } else {
+            self.say_command(input)
+        }
+    }
 
-    for opt in opts {
-        match opt {
-            SayArg::Speed(n) => saycmd.speed = n,
-            SayArg::Pitch(n) => saycmd.pitch = n,
-            SayArg::Wait(n) => saycmd.wait = n,
-            SayArg::Start(n) => saycmd.start = n,
-            SayArg::Duration(n) => saycmd.duration = Some(n),
-            SayArg::Stop => saycmd.stop = true,
-            SayArg::AudioFilter(af) => saycmd.audio_filter = Some(af),
+    /// Parses a sequence. A `nested` sequence is a lane of a group and also ends before `&` or
+    /// `)`.
+    fn say_commands(
+        &self,
+        mut input: &'a str,
+        nested: bool,
+    ) -> Result<(&'a str, Vec<SayNode>), Diagnostic> {
+        let mut nodes = Vec::new();
+        loop {
+            let rest = if let Ok((rest, (name, _))) = pair(variable, assign)(input) {
+                self.binding(rest, name)?
+            } else {
+                let (rest, node) = self.say_node(input)?;
+                nodes.push(node);
+                rest
+            };
+            if rest.is_empty() || (nested && rest.starts_with(['&', ')'])) {
+                return Ok((rest, nodes));
+            }
+            input = rest;
         }
     }
+}
+
+fn repeated(node: SayNode, count: u32) -> SayNode {
+    if count == 1 {
+        node
+    } else {
+        SayNode::Repeat(Repeat {
+            node: Box::new(node),
+            count,
+        })
+    }
+}
 
-    Ok((input, saycmd))
+/// Extracts the position where a (complete) parser gave up.
+const fn failed_at(e: nom::Err<nom::error::Error<&str>>) -> &str {
+    match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.input,
+        nom::Err::Incomplete(_) => "",
+    }
 }
 
-fn say_commands(input: &str) -> IResult<&str, Vec<SayCommand>> {
-    many1(say_command)(input)
+/// Guesses what was meant by a token that could not be read as an argument.
+fn classify_unexpected(token: &str) -> DiagnosticKind {
+    match token.chars().next() {
+        Some(c) if c.is_ascii_digit() || "@pvwsdfLR*".contains(c) => DiagnosticKind::BadArgument,
+        _ => DiagnosticKind::UnknownOption,
+    }
 }
 
 /// A combinator that takes a parser `inner` and produces a parser that also consumes both leading and
@@ -242,13 +1571,17 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::path::PathBuf;
+
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
     fn test_parse_single_command_without_options() {
         assert_eq!(
             SayCommands::from_str("a").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .build()
@@ -257,7 +1590,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a;").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .build()
@@ -266,7 +1599,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str(" a  ; ").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .build()
@@ -275,7 +1608,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a|").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .action(Action::Concat)
@@ -285,7 +1618,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str(" a  | ").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .action(Action::Concat)
@@ -299,7 +1632,7 @@ mod test {
     fn test_parse_single_command_with_options() {
         assert_eq!(
             SayCommands::from_str("a 50").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(50)
@@ -309,7 +1642,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str(" a  50 ").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(50)
@@ -319,7 +1652,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a @50").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(50)
@@ -329,7 +1662,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a @50 p10 w0.1 s0.2 d0.3 s").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(50)
@@ -344,7 +1677,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a@50p10w0.1s0.2d0.3s").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(50)
@@ -363,7 +1696,7 @@ mod test {
     fn test_parse_multiple_commands_without_options() {
         assert_eq!(
             SayCommands::from_str("a; b; c").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .build()
@@ -380,7 +1713,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a;b;c;").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .build()
@@ -397,7 +1730,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a|b|c|").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .action(Action::Concat)
@@ -422,7 +1755,7 @@ mod test {
         assert_eq!(
             SayCommands::from_str("a @50 p10 w0.1 s0.2 d0.3 s;b @100 p20 w0.2 s0.3 d0.4 s;")
                 .unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(50)
@@ -451,7 +1784,7 @@ mod test {
     fn test_prioritize_latter_option() {
         assert_eq!(
             SayCommands::from_str("a 10 20").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(20)
@@ -461,7 +1794,7 @@ mod test {
         );
         assert_eq!(
             SayCommands::from_str("a 10 20 30").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(30)
@@ -473,14 +1806,28 @@ mod test {
 
     #[test]
     fn test_parse_fails_for_usual_text() {
-        assert!(SayCommands::from_str("テスト").is_err());
+        assert!(SayCommands::from_str("テストです。").is_err());
         assert!(SayCommands::from_str("This is a test").is_err());
     }
 
+    #[test]
+    fn test_parse_unicode_names() {
+        let saycmds =
+            SayCommands::from_str("さいのう 120; ｻｲﾉｳ！ p80| ＳＡＩＮＯＵ＃ｲﾝﾄﾛ").unwrap();
+        assert_eq!(
+            saycmds
+                .iter()
+                .map(|cmd| cmd.name.as_str())
+                .collect::<Vec<_>>(),
+            ["さいのう", "ｻｲﾉｳ！", "ＳＡＩＮＯＵ＃ｲﾝﾄﾛ"]
+        );
+        assert_eq!(leading_sound_name("テスト。"), Some("テスト"));
+    }
+
     #[test]
     fn test_to_string() {
         assert_eq!(
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .build()
@@ -490,7 +1837,7 @@ mod test {
             "a".to_string()
         );
         assert_eq!(
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .speed(50)
@@ -516,7 +1863,7 @@ mod test {
             "a 50 p10 w0.2 s0.3 d0.4 s; b p20 w0.3 s0.4 d0.5 s".to_string()
         );
         assert_eq!(
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_string())
                     .action(Action::Concat)
@@ -534,7 +1881,7 @@ mod test {
                     .unwrap(),
             ])
             .to_string(),
-            "a| b| c".to_string()
+            "a| b| c|".to_string()
         );
     }
 
@@ -542,10 +1889,10 @@ mod test {
     fn test_audio_filter() {
         assert_eq!(
             SayCommands::from_str("a af=aecho=0.8:0.88:60:0.4").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_owned())
-                    .audio_filter(Some("aecho=0.8:0.88:60:0.4".to_owned()))
+                    .audio_filter(Some("aecho=0.8:0.88:60:0.4".parse().unwrap()))
                     .build()
                     .unwrap()
             ])
@@ -553,10 +1900,10 @@ mod test {
 
         assert_eq!(
             SayCommands::from_str("a af=aecho=0.8:0.9:1000|1800:0.3|0.25 | b").unwrap(),
-            SayCommands(vec![
+            SayCommands::from(vec![
                 SayCommandBuilder::default()
                     .name("a".to_owned())
-                    .audio_filter(Some("aecho=0.8:0.9:1000|1800:0.3|0.25".to_owned()))
+                    .audio_filter(Some("aecho=0.8:0.9:1000|1800:0.3|0.25".parse().unwrap()))
                     .action(Action::Concat)
                     .build()
                     .unwrap(),
@@ -566,5 +1913,773 @@ mod test {
                     .unwrap(),
             ])
         );
+
+        let diag = SayCommands::from_str("a af=amovie=/etc/passwd; b").unwrap_err();
+        assert_eq!(diag.span, 2..23);
+        assert!(matches!(
+            diag.kind,
+            DiagnosticKind::RejectedAudioFilter { .. }
+        ));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let diag = SayCommands::from_str("a 120 pX").unwrap_err();
+        assert_eq!(diag.span, 6..8);
+        assert_eq!(diag.kind, DiagnosticKind::BadArgument);
+        assert_eq!(
+            diag.render("a 120 pX"),
+            "a 120 pX\n      ^^ invalid argument"
+        );
+
+        let diag = SayCommands::from_str("a; b xyz").unwrap_err();
+        assert_eq!(diag.span, 5..8);
+        assert_eq!(diag.kind, DiagnosticKind::UnknownOption);
+
+        let diag = SayCommands::from_str("a; ;").unwrap_err();
+        assert_eq!(diag.span, 3..4);
+        assert_eq!(diag.kind, DiagnosticKind::ExpectedSoundName);
+    }
+
+    #[test]
+    fn test_unknown_sounds() {
+        let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let storage = SoundStorage::load(sound_dir);
+        let saycmds = SayCommands::from_str("d; sainuo 120; sine440; _").unwrap();
+        let diags = saycmds.unknown_sounds(&storage);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].span, 3..9);
+        match &diags[0].kind {
+            DiagnosticKind::UnknownSound { suggestions } => {
+                assert_eq!(suggestions.len(), 3);
+                assert_eq!(suggestions[0], "sainou");
+            }
+            kind => panic!("unexpected diagnostic: {kind:?}"),
+        }
+    }
+
+    #[test]
+    fn test_mentions() {
+        let known = |name: &str| name == "a";
+        let mentions = |source: &str| SayCommands::from_str(source).unwrap().mentions(&known);
+        assert!(!mentions("b; c | d"));
+        assert!(mentions("b; (c & a)*2"));
+        assert!(mentions("{b, a}"));
+        assert!(mentions("{*}"));
+        assert!(mentions("seq 120bpm b:x... a:..x."));
+        assert!(!mentions("$x = a; b"));
+    }
+
+    #[test]
+    fn test_volume() {
+        let mut saycmds = SayCommands::from_str("a v50; b 120 v250").unwrap();
+        assert_eq!(
+            saycmds,
+            SayCommands::from(vec![
+                SayCommandBuilder::default()
+                    .name("a".to_owned())
+                    .volume(50)
+                    .build()
+                    .unwrap(),
+                SayCommandBuilder::default()
+                    .name("b".to_owned())
+                    .speed(120)
+                    .volume(250)
+                    .build()
+                    .unwrap(),
+            ])
+        );
+        assert_eq!(saycmds.to_string(), "a v50; b 120 v250");
+
+        saycmds.sanitize();
+        assert_eq!(saycmds.to_string(), "a v50; b 120 v200");
+
+        let mut saycmds = SayCommands::from_str("a 0; b 5; c 5000").unwrap();
+        saycmds.sanitize();
+        assert_eq!(saycmds.to_string(), "a 10; b 10; c 1000");
+    }
+
+    #[test]
+    fn test_layer() {
+        let saycmds = SayCommands::from_str("(a & b 120 & c; d) | e").unwrap();
+        assert_eq!(
+            saycmds,
+            SayCommands::from(vec![
+                SayNode::Layer(Layer {
+                    lanes: vec![
+                        SayCommands::from(vec![
+                            SayCommandBuilder::default()
+                                .name("a".to_owned())
+                                .build()
+                                .unwrap()
+                        ]),
+                        SayCommands::from(vec![
+                            SayCommandBuilder::default()
+                                .name("b".to_owned())
+                                .speed(120)
+                                .build()
+                                .unwrap()
+                        ]),
+                        SayCommands::from(vec![
+                            SayCommandBuilder::default()
+                                .name("c".to_owned())
+                                .build()
+                                .unwrap(),
+                            SayCommandBuilder::default()
+                                .name("d".to_owned())
+                                .build()
+                                .unwrap(),
+                        ]),
+                    ],
+                    action: Action::Concat,
+                }),
+                SayNode::Command(
+                    SayCommandBuilder::default()
+                        .name("e".to_owned())
+                        .build()
+                        .unwrap()
+                ),
+            ])
+        );
+        assert_eq!(saycmds.to_string(), "(a & b 120 & c; d)| e");
+        assert_eq!(
+            saycmds
+                .iter()
+                .map(|cmd| cmd.name.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e"]
+        );
+        assert_eq!(
+            saycmds
+                .clone()
+                .into_iter()
+                .map(|cmd| cmd.name)
+                .collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e"]
+        );
+
+        let saycmds = SayCommands::from_str("a; (b | (c & d) & e)").unwrap();
+        assert_eq!(saycmds.to_string(), "a; (b| (c & d) & e)");
+        assert_eq!(
+            SayCommands::from_str(&saycmds.to_string()).unwrap(),
+            saycmds
+        );
+    }
+
+    #[test]
+    fn test_layer_diagnostics() {
+        let diag = SayCommands::from_str("a; (b & c").unwrap_err();
+        assert_eq!(diag.span, 3..4);
+        assert_eq!(diag.kind, DiagnosticKind::UnclosedGroup);
+
+        let diag = SayCommands::from_str("(a & )").unwrap_err();
+        assert_eq!(diag.span, 5..6);
+        assert_eq!(diag.kind, DiagnosticKind::ExpectedSoundName);
+
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        let saycmds = SayCommands::from_str(&nested(MAX_GROUP_DEPTH)).unwrap();
+        assert_eq!(saycmds.depth(), MAX_GROUP_DEPTH);
+        let diag = SayCommands::from_str(&nested(MAX_GROUP_DEPTH + 1)).unwrap_err();
+        assert_eq!(diag.span, MAX_GROUP_DEPTH..MAX_GROUP_DEPTH + 1);
+        assert_eq!(diag.kind, DiagnosticKind::NestedTooDeeply);
+        let diag = SayCommands::from_str(&"(".repeat(2000)).unwrap_err();
+        assert_eq!(diag.kind, DiagnosticKind::NestedTooDeeply);
+
+        // Variables are as deep as the groups they were bound to.
+        let source = format!("$x = {}; ($x)", nested(MAX_GROUP_DEPTH));
+        let diag = SayCommands::from_str(&source).unwrap_err();
+        assert_eq!(diag.kind, DiagnosticKind::NestedTooDeeply);
+    }
+
+    #[test]
+    fn test_repeat() {
+        let saycmds = SayCommands::from_str("a*4| b").unwrap();
+        assert_eq!(
+            saycmds,
+            SayCommands::from(vec![
+                SayNode::Repeat(Repeat {
+                    node: Box::new(SayNode::Command(
+                        SayCommandBuilder::default()
+                            .name("a".to_owned())
+                            .action(Action::Concat)
+                            .build()
+                            .unwrap()
+                    )),
+                    count: 4,
+                }),
+                SayNode::Command(
+                    SayCommandBuilder::default()
+                        .name("b".to_owned())
+                        .build()
+                        .unwrap()
+                ),
+            ])
+        );
+        assert_eq!(saycmds.to_string(), "a*4| b");
+        assert_eq!(saycmds.expanded_count(), 5);
+        assert_eq!(saycmds.iter().count(), 2);
+
+        let saycmds = SayCommands::from_str("(a 120 | b p80)*3; c *2 w0.5").unwrap();
+        assert_eq!(saycmds.to_string(), "(a 120| b p80)*3; c w0.5*2");
+        assert_eq!(saycmds.expanded_count(), 8);
+        assert_eq!(
+            SayCommands::from_str(&saycmds.to_string()).unwrap(),
+            saycmds
+        );
+
+        // A count of one is the same as no count at all.
+        assert_eq!(
+            SayCommands::from_str("a*1").unwrap(),
+            SayCommands::from_str("a").unwrap()
+        );
+
+        let diag = SayCommands::from_str("a*0").unwrap_err();
+        assert_eq!(diag.span, 1..3);
+        assert_eq!(diag.kind, DiagnosticKind::BadArgument);
+    }
+
+    #[test]
+    fn test_sanitize_caps_expanded_commands() {
+        let mut saycmds = SayCommands::from_str("a*1000").unwrap();
+        saycmds.sanitize();
+        assert_eq!(saycmds.to_string(), format!("a*{MAX_EXPANDED_COMMANDS}"));
+
+        let mut saycmds = SayCommands::from_str("a*99; (b & c)*1000; d").unwrap();
+        saycmds.sanitize();
+        assert_eq!(saycmds.to_string(), "a*99; (b)");
+        assert_eq!(saycmds.expanded_count(), MAX_EXPANDED_COMMANDS);
+
+        let mut saycmds = SayCommands::from_str("((a*1000)*1000)*1000").unwrap();
+        saycmds.sanitize();
+        assert_eq!(saycmds.expanded_count(), MAX_EXPANDED_COMMANDS);
+
+        // Repeating a sequence without hits plays nothing, but is capped all the same.
+        for source in [
+            "seq 120bpm a:....*4294967295",
+            "(seq 120bpm a:....*4294967295)*4294967295",
+        ] {
+            let mut saycmds = SayCommands::from_str(source).unwrap();
+            assert!(saycmds.expanded_count() >= u32::MAX as usize, "{source}");
+            saycmds.sanitize();
+            assert_eq!(saycmds.expanded_count(), MAX_EXPANDED_COMMANDS, "{source}");
+        }
+    }
+
+    #[test]
+    fn test_choice() {
+        let saycmds = SayCommands::from_str("{a, b,c} 120 | {*}").unwrap();
+        assert_eq!(
+            saycmds,
+            SayCommands::from(vec![
+                SayNode::Choice(Choice {
+                    candidates: Candidates::Sounds(vec![
+                        "a".to_owned(),
+                        "b".to_owned(),
+                        "c".to_owned()
+                    ]),
+                    command: SayCommandBuilder::default()
+                        .speed(120)
+                        .action(Action::Concat)
+                        .build()
+                        .unwrap(),
+                }),
+                SayNode::Choice(Choice {
+                    candidates: Candidates::Any,
+                    command: SayCommand::default(),
+                }),
+            ])
+        );
+        assert_eq!(saycmds.to_string(), "{a,b,c} 120| {*}");
+        assert_eq!(saycmds.iter().count(), 0);
+
+        let diag = SayCommands::from_str("a; {}").unwrap_err();
+        assert_eq!(diag.span, 3..5);
+        assert_eq!(diag.kind, DiagnosticKind::ExpectedSoundName);
+    }
+
+    #[test]
+    fn test_resolve_choices() {
+        let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let storage = SoundStorage::load(sound_dir);
+        let saycmds = SayCommands::from_str("{a,b,c} 120 | ({*} & {x})*2").unwrap();
+
+        let mut resolved = saycmds.clone();
+        resolved.resolve_choices(&storage, Some(42));
+        let names: Vec<_> = resolved.iter().map(|cmd| cmd.name.clone()).collect();
+        assert_eq!(names.len(), 3);
+        assert!(["a", "b", "c"].contains(&names[0].as_str()));
+        assert!(storage.get(&names[1]).is_some());
+        assert_eq!(names[2], "x");
+        assert_eq!(resolved.iter().next().unwrap().speed, 120);
+
+        // The same seed picks the same sounds.
+        for _ in 0..10 {
+            let mut again = saycmds.clone();
+            again.resolve_choices(&storage, Some(42));
+            assert_eq!(again, resolved);
+        }
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let lookup = |name: &str| match name {
+            "intro" => Some("a 120; b p80| c".to_owned()),
+            "twice" => Some("intro; intro".to_owned()),
+            "loop" => Some("x; twice; loop".to_owned()),
+            "many" => Some("many2; many2; many2; many2".to_owned()),
+            "many2" => Some("many3; many3; many3; many3".to_owned()),
+            "many3" => Some("(twice & twice)".to_owned()),
+            "deep" => Some(format!("{}a{}", "(".repeat(10), ")".repeat(10))),
+            "deeper" => Some("((((((deep))))))".to_owned()),
+            _ => None,
+        };
+
+        let mut saycmds = SayCommands::from_str("z; twice*2 | intro").unwrap();
+        saycmds.expand_macros(&lookup).unwrap();
+        assert_eq!(
+            saycmds.to_string(),
+            "z; ((a 120; b p80| c); (a 120; b p80| c))*2| (a 120; b p80| c)"
+        );
+
+        let diag = SayCommands::from_str("a; loop")
+            .unwrap()
+            .expand_macros(&lookup)
+            .unwrap_err();
+        assert_eq!(diag.span, 3..7);
+        assert_eq!(
+            diag.kind,
+            DiagnosticKind::Macro {
+                name: "loop".to_owned(),
+                reason: "expands to itself".to_owned()
+            }
+        );
+
+        let diag = SayCommands::from_str("many")
+            .unwrap()
+            .expand_macros(&lookup)
+            .unwrap_err();
+        assert!(matches!(
+            diag.kind,
+            DiagnosticKind::Macro { reason, .. } if reason == "expands to too many macros"
+        ));
+
+        let mut saycmds = SayCommands::from_str("deep").unwrap();
+        saycmds.expand_macros(&lookup).unwrap();
+        assert_eq!(saycmds.depth(), 11);
+        let diag = SayCommands::from_str("deeper")
+            .unwrap()
+            .expand_macros(&lookup)
+            .unwrap_err();
+        assert!(matches!(
+            diag.kind,
+            DiagnosticKind::Macro { reason, .. } if reason == "nests groups too deeply"
+        ));
+    }
+
+    #[test]
+    fn test_effects() {
+        let saycmds = SayCommands::from_str("a rev fi0.5 fo1.2; b fo0.3").unwrap();
+        assert_eq!(
+            saycmds,
+            SayCommands::from(vec![
+                SayCommandBuilder::default()
+                    .name("a".to_owned())
+                    .reverse(true)
+                    .fade_in(500)
+                    .fade_out(1200)
+                    .build()
+                    .unwrap(),
+                SayCommandBuilder::default()
+                    .name("b".to_owned())
+                    .fade_out(300)
+                    .build()
+                    .unwrap(),
+            ])
+        );
+        assert_eq!(saycmds.to_string(), "a rev fi0.5 fo1.2; b fo0.3");
+
+        let diag = SayCommands::from_str("a fiX").unwrap_err();
+        assert_eq!(diag.span, 2..5);
+        assert_eq!(diag.kind, DiagnosticKind::BadArgument);
+    }
+
+    #[test]
+    fn test_pan() {
+        let saycmds = SayCommands::from_str("a L50 | b R30; c L0; d R500").unwrap();
+        assert_eq!(
+            saycmds.iter().map(|cmd| cmd.pan).collect::<Vec<_>>(),
+            [-50, 30, 0, 100]
+        );
+        assert_eq!(saycmds.to_string(), "a L50| b R30; c; d R100");
+    }
+
+    #[test]
+    fn test_at() {
+        let saycmds = SayCommands::from_str("a @t1.25 120; b @50 @t0.5; c").unwrap();
+        assert_eq!(
+            saycmds,
+            SayCommands::from(vec![
+                SayCommandBuilder::default()
+                    .name("a".to_owned())
+                    .at(Some(1250))
+                    .speed(120)
+                    .build()
+                    .unwrap(),
+                SayCommandBuilder::default()
+                    .name("b".to_owned())
+                    .speed(50)
+                    .at(Some(500))
+                    .build()
+                    .unwrap(),
+                SayCommandBuilder::default()
+                    .name("c".to_owned())
+                    .build()
+                    .unwrap(),
+            ])
+        );
+        assert_eq!(saycmds.to_string(), "a @t1.25 120; b @t0.5 50; c");
+    }
+
+    #[test]
+    fn test_variables() {
+        let saycmds =
+            SayCommands::from_str("$x = a 120 p80; $y = ($x | b); $x*2| $y; $x = c; $x").unwrap();
+        assert_eq!(saycmds.to_string(), "a 120 p80*2| (a 120 p80| b); c");
+        // Referenced commands point at their definition.
+        assert_eq!(
+            saycmds
+                .iter()
+                .map(|cmd| cmd.span.range())
+                .collect::<Vec<_>>(),
+            [5..6, 5..6, 27..28, 46..47]
+        );
+
+        assert!(SayCommands::from_str("$x = a;").unwrap().is_empty());
+
+        let diag = SayCommands::from_str("a; $x; $x = b").unwrap_err();
+        assert_eq!(diag.span, 3..5);
+        assert_eq!(diag.kind, DiagnosticKind::UndefinedVariable);
+
+        let diag = SayCommands::from_str("$x = a; $x 120").unwrap_err();
+        assert_eq!(diag.span, 11..14);
+        assert_eq!(diag.kind, DiagnosticKind::BadArgument);
+    }
+
+    #[test]
+    fn test_pitch_notation() {
+        let saycmds =
+            SayCommands::from_str("a p+3st; b p-12st; c p+50c; d p80; e p+1200c").unwrap();
+        assert_eq!(
+            saycmds.iter().map(|cmd| cmd.pitch).collect::<Vec<_>>(),
+            [
+                Pitch::Cents(300),
+                Pitch::Cents(-1200),
+                Pitch::Cents(50),
+                Pitch::Percent(80),
+                Pitch::Cents(1200),
+            ]
+        );
+        assert_eq!(
+            saycmds.to_string(),
+            "a p+3st; b p-12st; c p+50c; d p80; e p+12st"
+        );
+        assert!((Pitch::Cents(-1200).ratio() - 0.5).abs() < 1e-9);
+        assert!((Pitch::Percent(200).semitones() - 12.0).abs() < 1e-9);
+
+        let diag = SayCommands::from_str("a p+3").unwrap_err();
+        assert_eq!(diag.span, 2..5);
+        assert_eq!(diag.kind, DiagnosticKind::BadArgument);
+    }
+
+    #[test]
+    fn test_sanitize_clamps_pitch() {
+        let mut saycmds =
+            SayCommands::from_str("a p200; b p300; c p0; d p+13st; e p-50st").unwrap();
+        saycmds.sanitize();
+        assert_eq!(
+            saycmds.to_string(),
+            "a p200; b p+12st; c p-48st; d p+12st; e p-48st"
+        );
+    }
+
+    #[test]
+    fn test_time_notation() {
+        let saycmds =
+            SayCommands::from_str("a s-2.5; b s1:23.4 d0:02; c d3 dEnd; d w1:00; e s-1:00")
+                .unwrap();
+        let cmds: Vec<_> = saycmds.iter().collect();
+        assert_eq!(cmds[0].start, -2500);
+        assert_eq!((cmds[1].start, cmds[1].duration), (83400, Some(2000)));
+        assert_eq!(cmds[2].duration, None);
+        assert_eq!(cmds[3].wait, 60000);
+        assert_eq!(cmds[4].start, -60000);
+        assert_eq!(saycmds.to_string(), "a s-2.5; b s83.4 d2; c; d w60; e s-60");
+
+        let sound = Duration::from_secs(10);
+        assert_eq!(cmds[0].start_offset(sound), Duration::from_millis(7500));
+        assert_eq!(cmds[1].start_offset(sound), Duration::from_millis(83400));
+        assert_eq!(cmds[4].start_offset(sound), Duration::ZERO);
+
+        assert!(SayCommands::from_str("a s1:-3").is_err());
+    }
+
+    #[test]
+    fn test_resolve_markers() {
+        let sound_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("tests/sound");
+        let storage = SoundStorage::load(sound_dir);
+
+        let mut saycmds = SayCommands::from_str(
+            "sainou#intro; Sainou#INTRO s0.1; sainou#intro s-0.1 d1; sainou#intro d0.2 120; \
+             sainou#nothing",
+        )
+        .unwrap();
+        let diags = saycmds.unknown_sounds(&storage);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].span, 79..93);
+
+        saycmds.resolve_markers(&storage);
+        assert_eq!(
+            saycmds.to_string(),
+            "sainou s0.2 d0.5; sainou s0.3 d0.4; sainou s0.6 d0.1; sainou 120 s0.2 d0.2; \
+             sainou#nothing"
+        );
+    }
+
+    #[test]
+    fn test_seq() {
+        let saycmds =
+            SayCommands::from_str("a; seq 120bpm kick:x...x... 80 snare:....x v50 L20| b").unwrap();
+        let SayNode::Seq(seq) = saycmds.nodes().nth(1).unwrap() else {
+            panic!("not a sequencer");
+        };
+        assert_eq!(seq.bpm, 120);
+        assert_eq!(seq.step_duration(), Duration::from_millis(125));
+        assert_eq!(seq.len(), 8);
+        assert_eq!(seq.action, Action::Concat);
+        assert_eq!(
+            seq.tracks[0].command,
+            SayCommandBuilder::default()
+                .name("kick".to_owned())
+                .speed(80)
+                .build()
+                .unwrap()
+        );
+        assert_eq!(seq.tracks[1].steps, [false, false, false, false, true]);
+        assert_eq!(
+            (seq.tracks[1].command.volume, seq.tracks[1].command.pan),
+            (50, -20)
+        );
+        assert_eq!(seq.tracks[1].command.span.range(), 31..36);
+
+        assert_eq!(
+            saycmds.to_string(),
+            "a; seq 120bpm kick:x...x... 80 snare:....x v50 L20| b"
+        );
+        assert_eq!(
+            saycmds
+                .iter()
+                .map(|cmd| cmd.name.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "kick", "snare", "b"]
+        );
+        assert_eq!(saycmds.expanded_count(), 5);
+
+        // Without a tempo, `seq` is an ordinary sound.
+        let saycmds = SayCommands::from_str("seq 120; seq*2").unwrap();
+        assert_eq!(saycmds.to_string(), "seq 120; seq*2");
+
+        let saycmds = SayCommands::from_str("seq 90bpm a:x.x. b:.x*2").unwrap();
+        assert_eq!(saycmds.to_string(), "seq 90bpm a:x.x. b:.x*2");
+        assert_eq!(saycmds.expanded_count(), 6);
+
+        let diag = SayCommands::from_str("seq 120bpm 80").unwrap_err();
+        assert_eq!(diag.span, 11..13);
+        assert_eq!(diag.kind, DiagnosticKind::ExpectedSoundName);
+    }
+
+    #[test]
+    fn test_sanitize_truncates_seq() {
+        let mut saycmds = SayCommands::from_str(&format!(
+            "seq 120bpm a:{} b:x",
+            "x".repeat(MAX_EXPANDED_COMMANDS + 10)
+        ))
+        .unwrap();
+        saycmds.sanitize();
+        assert_eq!(saycmds.expanded_count(), MAX_EXPANDED_COMMANDS);
+        // The earliest hits are kept.
+        let SayNode::Seq(seq) = saycmds.nodes().next().unwrap() else {
+            panic!("not a sequencer");
+        };
+        assert_eq!(seq.tracks[1].steps, [true]);
+        assert_eq!(
+            seq.tracks[0].steps.iter().filter(|hit| **hit).count(),
+            MAX_EXPANDED_COMMANDS - 1
+        );
+    }
+
+    #[test]
+    fn test_canonical_form() {
+        for (source, canonical) in [
+            ("a|", "a|"),
+            ("(a | & b)", "(a| & b)"),
+            (
+                "a w1.234 s-0.5 d0.001 fi2.05",
+                "a w1.234 s-0.5 d0.001 fi2.05",
+            ),
+            ("a s1:23.456", "a s83.456"),
+            (
+                "a af=volume=volume=0.5 | b af=highpass=f=200 *2",
+                "a af=volume=0.5 | b af=highpass=200 *2",
+            ),
+            ("a*2*3; (b)*2*2", "a*2*3; (b)*2*2"),
+            ("$x = a", ""),
+        ] {
+            let saycmds = SayCommands::from_str(source).unwrap();
+            assert_eq!(saycmds.to_string(), canonical);
+            assert_eq!(SayCommands::from_str(canonical).unwrap(), saycmds);
+        }
+        assert_eq!(SayCommands::from_str("a*2*3").unwrap().expanded_count(), 6);
+        // Only `seq` followed by a space starts a sequencer.
+        assert!(matches!(
+            SayCommands::from_str("seq1bpm").unwrap().nodes().next(),
+            Some(SayNode::Command(_))
+        ));
+    }
+
+    fn arb_name() -> impl Strategy<Value = String> {
+        "[a-z_][a-z0-9_]{0,5}"
+    }
+
+    fn arb_action() -> impl Strategy<Value = Action> {
+        prop_oneof![Just(Action::Synthesize), Just(Action::Concat)]
+    }
+
+    fn arb_command() -> impl Strategy<Value = SayCommand> {
+        let pitch = prop_oneof![
+            Just(Pitch::default()),
+            any::<u32>().prop_map(Pitch::Percent),
+            any::<i32>().prop_map(Pitch::Cents),
+            (MIN_PITCH_SEMITONES..=MAX_PITCH_SEMITONES).prop_map(|st| Pitch::Cents(st * 100)),
+        ];
+        let audio_filter = prop::option::of(
+            prop::sample::select(vec![
+                "volume=0.5",
+                "highpass=f=200,volume=0.5",
+                "aecho=0.8:0.9:1000|1800:0.3|0.25",
+            ])
+            .prop_map(|af| AudioFilter::from_str(af).unwrap()),
+        );
+        (
+            (
+                arb_name(),
+                prop::option::of(any::<u32>()),
+                prop_oneof![Just(100), any::<u32>()],
+                pitch,
+                prop_oneof![Just(100), any::<u32>()],
+            ),
+            (
+                prop_oneof![Just(0), any::<u32>()],
+                prop_oneof![Just(0), any::<i32>()],
+                prop::option::of(any::<u32>()),
+                any::<bool>(),
+                any::<bool>(),
+            ),
+            (
+                prop_oneof![Just(0), any::<u32>()],
+                prop_oneof![Just(0), any::<u32>()],
+                -100..=100,
+                audio_filter,
+                arb_action(),
+            ),
+        )
+            .prop_map(
+                |(
+                    (name, at, speed, pitch, volume),
+                    (wait, start, duration, stop, reverse),
+                    (fade_in, fade_out, pan, audio_filter, action),
+                )| SayCommand {
+                    name,
+                    speed,
+                    pitch,
+                    volume,
+                    wait,
+                    start,
+                    duration,
+                    stop,
+                    reverse,
+                    fade_in,
+                    fade_out,
+                    pan,
+                    at,
+                    action,
+                    audio_filter,
+                    ..Default::default()
+                },
+            )
+    }
+
+    fn arb_node() -> impl Strategy<Value = SayNode> {
+        let candidates = prop_oneof![
+            Just(Candidates::Any),
+            prop::collection::vec(arb_name(), 1..4).prop_map(Candidates::Sounds),
+        ];
+        let choice = (candidates, arb_command()).prop_map(|(candidates, mut command)| {
+            command.name.clear();
+            SayNode::Choice(Choice {
+                candidates,
+                command,
+            })
+        });
+        let track = (arb_command(), prop::collection::vec(any::<bool>(), 1..17)).prop_map(
+            |(command, steps)| SeqTrack {
+                command: SayCommand {
+                    action: Action::Synthesize,
+                    ..command
+                },
+                steps,
+            },
+        );
+        let seq = (
+            1..=MAX_SEQ_BPM,
+            prop::collection::vec(track, 1..4),
+            arb_action(),
+        )
+            .prop_map(|(bpm, tracks, action)| {
+                SayNode::Seq(Seq {
+                    bpm,
+                    tracks,
+                    action,
+                })
+            });
+        let leaf = prop_oneof![arb_command().prop_map(SayNode::Command), choice, seq];
+        leaf.prop_recursive(3, 24, 4, |inner| {
+            let lane = prop::collection::vec(inner.clone(), 1..4).prop_map(SayCommands::from);
+            prop_oneof![
+                (prop::collection::vec(lane, 1..4), arb_action())
+                    .prop_map(|(lanes, action)| SayNode::Layer(Layer { lanes, action })),
+                (inner, 2..10u32).prop_map(|(node, count)| {
+                    SayNode::Repeat(Repeat {
+                        node: Box::new(node),
+                        count,
+                    })
+                }),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(
+            saycmds in prop::collection::vec(arb_node(), 0..4).prop_map(SayCommands::from)
+        ) {
+            let printed = saycmds.to_string();
+            prop_assert_eq!(SayCommands::from_str(&printed), Ok(saycmds), "{}", printed);
+        }
     }
 }
@@ -0,0 +1,96 @@
+//! Built-in synthetic sounds that are generated on the fly instead of being read from the sound
+//! directory.
+//!
+//! Their names are reserved: `_` (silence), `sine<Hz>`, `square<Hz>` and `noise`.
+
+use std::{f64::consts::PI, time::Duration};
+
+use rand::Rng as _;
+
+use crate::dsp::Audio;
+
+/// Length of a synthetic sound played without a `d` argument.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(1);
+
+/// Audible frequencies accepted by `sine<Hz>` and `square<Hz>`.
+const FREQUENCY_RANGE_HZ: std::ops::RangeInclusive<u32> = 20..=20000;
+
+/// Sample rate the sounds are generated at.
+pub const SAMPLE_RATE_HZ: u32 = 48000;
+
+/// Peak amplitude of tones and noise, leaving room for layering them with other sounds.
+const AMPLITUDE: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synth {
+    Silence,
+    Sine(u32),
+    Square(u32),
+    Noise,
+}
+
+impl Synth {
+    /// Returns the synthetic sound called `name`, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        let tone = |prefix: &str| {
+            name.strip_prefix(prefix)
+                .filter(|hz| hz.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|hz| hz.parse().ok())
+                .filter(|hz| FREQUENCY_RANGE_HZ.contains(hz))
+        };
+        match name.as_str() {
+            "_" => Some(Self::Silence),
+            "noise" => Some(Self::Noise),
+            _ => tone("sine")
+                .map(Self::Sine)
+                .or_else(|| tone("square").map(Self::Square)),
+        }
+    }
+
+    /// Generates `duration` of the sound as mono audio.
+    pub fn generate(self, duration: Duration) -> Audio {
+        let frames = (duration.as_secs_f64() * SAMPLE_RATE_HZ as f64).round() as usize;
+        let phase = |i: usize, hz: u32| 2.0 * PI * hz as f64 * i as f64 / SAMPLE_RATE_HZ as f64;
+        let mut rng = rand::thread_rng();
+        let samples = (0..frames)
+            .map(|i| match self {
+                Self::Silence => 0.0,
+                Self::Sine(hz) => AMPLITUDE * phase(i, hz).sin(),
+                Self::Square(hz) => AMPLITUDE * phase(i, hz).sin().signum(),
+                Self::Noise => rng.gen_range(-AMPLITUDE..=AMPLITUDE),
+            } as f32)
+            .collect();
+        Audio::new(SAMPLE_RATE_HZ, 1, samples)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Synth::from_name("_"), Some(Synth::Silence));
+        assert_eq!(Synth::from_name("sine440"), Some(Synth::Sine(440)));
+        assert_eq!(Synth::from_name("Square220"), Some(Synth::Square(220)));
+        assert_eq!(Synth::from_name("noise"), Some(Synth::Noise));
+        assert_eq!(Synth::from_name("sine"), None);
+        assert_eq!(Synth::from_name("sine+440"), None);
+        assert_eq!(Synth::from_name("sine5"), None);
+        assert_eq!(Synth::from_name("sine99999999999"), None);
+        assert_eq!(Synth::from_name("sainou"), None);
+    }
+
+    #[test]
+    fn test_generate() {
+        let audio = Synth::Sine(440).generate(Duration::from_millis(500));
+        assert_eq!(audio.sample_rate_hz, SAMPLE_RATE_HZ);
+        assert_eq!(audio.channels, 1);
+        assert_eq!(audio.frames(), 24000);
+        assert!(audio.samples.iter().all(|s| s.abs() <= AMPLITUDE as f32));
+
+        let audio = Synth::Silence.generate(Duration::from_secs(1));
+        assert!(audio.samples.iter().all(|&s| s == 0.0));
+    }
+}
@@ -11,7 +11,7 @@ use prost::Message;
 use serde::Serialize;
 use tempfile::tempdir;
 
-use crate::{SoundStorage, sound::ToSoundsProto};
+use crate::{SoundStorage, macros::Macro, sound::ToSoundsProto};
 
 #[derive(Debug, Serialize)]
 struct Data {
@@ -46,13 +46,15 @@ pub fn gen_data_json_from_sound_dir<P: AsRef<Path>, Q: AsRef<Path>>(
     Ok(())
 }
 
-pub fn gen_sounds_bin_from_sound_dir<P: AsRef<Path>, Q: AsRef<Path>>(
-    sound_dir: P,
-    out_file: Q,
+pub fn gen_sounds_bin_from_storage<P: AsRef<Path>>(
+    storage: &SoundStorage,
+    macros: &[Macro],
+    out_file: P,
 ) -> anyhow::Result<()> {
-    let storage = SoundStorage::load(sound_dir);
+    let mut sounds = storage.files().cloned().to_sounds();
+    sounds.macros = macros.iter().map(Into::into).collect();
     let mut buf = Vec::new();
-    storage.files().cloned().to_sounds().encode(&mut buf)?;
+    sounds.encode(&mut buf)?;
     fs::write(out_file, buf)?;
     Ok(())
 }
@@ -74,11 +76,23 @@ pub async fn update_data_json<P: AsRef<Path>>(sound_dir: P) -> anyhow::Result<()
 }
 
 #[allow(clippy::future_not_send)]
-pub async fn update_sounds_bin<P: AsRef<Path>>(sound_dir: P) -> anyhow::Result<()> {
+pub async fn update_sounds_bin(
+    mut storage: SoundStorage,
+    macros: Vec<Macro>,
+) -> anyhow::Result<()> {
     let temp_dir = tempdir()?;
     let out_file = temp_dir.path().join("sounds.bin");
 
-    gen_sounds_bin_from_sound_dir(sound_dir, &out_file)?;
+    // Reloading picks up the latest uploads and deletions, and measuring the loudness of new
+    // sounds decodes them.
+    {
+        let out_file = out_file.clone();
+        tokio::task::spawn_blocking(move || {
+            storage.reload();
+            gen_sounds_bin_from_storage(&storage, &macros, out_file)
+        })
+        .await??;
+    }
 
     let data = fs::read(&out_file)?;
     let client = cloud_storage::Client::default();